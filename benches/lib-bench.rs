@@ -1,16 +1,14 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
-#[path = "../src/lib.rs"]
-mod lib;
-use lib::crc::*;
-use lib::gatewayprotocol::*;
-
-use lib::jenkins::*;
-use lib::rc4::*;
-use lib::soeprotocol::*;
-use lib::soeprotocol_functions::*;
-use lib::soeprotocol_packets_structs::*;
-use lib::utils::*;
+use h1emu_core::crc::*;
+use h1emu_core::gatewayprotocol::*;
+
+use h1emu_core::jenkins::*;
+use h1emu_core::rc4::*;
+use h1emu_core::soeprotocol::*;
+use h1emu_core::soeprotocol_functions::*;
+use h1emu_core::soeprotocol_packets_structs::*;
+use h1emu_core::utils::*;
 
 fn soeprotocol_utils_benchmarks(c: &mut Criterion) {
     let data_to_pack: Vec<u8> = [
@@ -182,7 +180,7 @@ fn soeprotocol_parse_benchmarks(c: &mut Criterion) {
 fn soeprotocol_pack_benchmarks(c: &mut Criterion) {
     let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
     let session_request_to_pack =
-        r#"{"crc_length":3,"session_id":1008176227,"udp_length":512,"protocol":"LoginUdp_9"}"#
+        r#"{"crc_length":3,"session_id":1008176227,"protocol_version":3,"udp_length":512,"protocol":"LoginUdp_9"}"#
             .to_string();
     let session_reply_to_pack =  r#"{"session_id":1008176227,"crc_seed":0,"crc_length":2,"encrypt_method":256,"udp_length":512}"#.to_string();
     let ping_to_pack: String = r#"{"name":"Ping"}"#.to_owned();
@@ -425,12 +423,10 @@ fn crc_legacy_benchmark(c: &mut Criterion) {
     c.bench_function("append_crc_legacy", |b| {
         b.iter(|| append_crc_legacy(black_box(&data), black_box(0)))
     });
-    drop(data);
     let data: [u8; 5] = [0, 21, 0, 0, 2];
     c.bench_function("crc32_legacy", |b| {
         b.iter(|| crc32_legacy(black_box(&data), black_box(0)))
     });
-    drop(data);
 }
 
 fn crc_benchmark(c: &mut Criterion) {
@@ -442,12 +438,10 @@ fn crc_benchmark(c: &mut Criterion) {
     c.bench_function("append_crc", |b| {
         b.iter(|| append_crc(black_box(&mut data.to_owned()), black_box(0)))
     });
-    drop(data);
     let mut data: Vec<u8> = [0, 21, 0, 0, 2].to_vec();
     c.bench_function("crc32", |b| {
         b.iter(|| crc32(black_box(&&mut data), black_box(0)))
     });
-    drop(data);
 }
 
 fn utils_benchmark(c: &mut Criterion) {
@@ -479,16 +473,16 @@ fn rc4_benchmark(c: &mut Criterion) {
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    // crc_legacy_benchmark(c);
-    // crc_benchmark(c);
-    // utils_benchmark(c);
-    // jooat_benchmark(c);
-    // rc4_benchmark(c);
+    crc_legacy_benchmark(c);
+    crc_benchmark(c);
+    utils_benchmark(c);
+    jooat_benchmark(c);
+    rc4_benchmark(c);
     soeprotocol_parse_benchmarks(c);
     soeprotocol_pack_benchmarks(c);
     soeprotocol_utils_benchmarks(c);
-    // gatewayprotocol_parse_benchmarks(c);
-    // gatewayprotocol_pack_benchmarks(c);
+    gatewayprotocol_parse_benchmarks(c);
+    gatewayprotocol_pack_benchmarks(c);
 }
 
 criterion_group!(benches, criterion_benchmark);
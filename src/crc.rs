@@ -2,9 +2,26 @@ use super::crc_table::CRC_TABLE;
 use byteorder::{BigEndian, WriteBytesExt};
 use wasm_bindgen::prelude::*;
 
+pub const DEFAULT_CRC_LENGTH: u8 = 2;
+pub const MAX_CRC_LENGTH: u8 = 4;
+
 pub fn append_crc(data: &mut Vec<u8>, crc_seed: u32) {
+    append_crc_with_length(data, crc_seed, DEFAULT_CRC_LENGTH);
+}
+
+// the crc footer is made of the crc_length lowest bytes of the crc, big endian
+pub fn append_crc_with_length(data: &mut Vec<u8>, crc_seed: u32, crc_length: u8) {
+    let crc_length = crc_length.min(MAX_CRC_LENGTH) as usize;
     let crc = crc32(&data, crc_seed as usize);
-    data.write_u16::<BigEndian>((crc & 0xffff) as u16).unwrap();
+    data.extend_from_slice(&crc.to_be_bytes()[4 - crc_length..]);
+}
+
+pub fn crc_mask(crc_length: u8) -> u32 {
+    match crc_length {
+        0 => 0,
+        1..=3 => (1 << (crc_length * 8)) - 1,
+        _ => u32::MAX,
+    }
 }
 
 pub fn crc32(data: &&mut Vec<u8>, crc_seed: usize) -> u32 {
@@ -50,8 +67,8 @@ pub fn crc32_legacy(data: &[u8], crc_seed: usize) -> u32 {
     index = (crc_seed >> 24) ^ crc as usize;
     crc = (crc >> 8) & 0x00ffffff;
     crc ^= CRC_TABLE[index & 0xff];
-    for i in 0..data.len() {
-        index = data[i] as usize ^ crc as usize;
+    for byte in data {
+        index = *byte as usize ^ crc as usize;
         crc = (crc >> 8) & 0x00ffffff;
         crc ^= CRC_TABLE[index & 0xff];
     }
@@ -102,4 +119,14 @@ mod tests {
             ]
         )
     }
+    #[test]
+    fn append_crc_with_length_test() {
+        let data: Vec<u8> = [0, 21, 0, 0, 2].to_vec();
+        let crc = super::crc32_legacy(&data, 0).to_be_bytes();
+        for crc_length in 0..=4 {
+            let mut data_with_crc = data.clone();
+            super::append_crc_with_length(&mut data_with_crc, 0, crc_length);
+            assert_eq!(data_with_crc[5..], crc[4 - crc_length as usize..]);
+        }
+    }
 }
@@ -1,9 +1,11 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt};
 use std::io::Cursor;
 use wasm_bindgen::prelude::*;
 
 use super::gatewayprotocol_packets_structs::*;
 use super::lib_utils::read_prefixed_string_le;
+use super::packing::PackInto;
+use super::protocol_errors::ProtocolError;
 
 #[wasm_bindgen]
 pub enum GatewayChannels {
@@ -15,15 +17,13 @@ pub enum GatewayChannels {
 }
 
 #[wasm_bindgen]
-pub struct GatewayProtocol {
-    wtr: Vec<u8>,
-}
+pub struct GatewayProtocol {}
 
 #[wasm_bindgen]
 impl GatewayProtocol {
     #[wasm_bindgen(constructor)]
     pub fn initialize() -> GatewayProtocol {
-        GatewayProtocol { wtr: vec![] }
+        GatewayProtocol {}
     }
     pub fn parse(&mut self, data: Vec<u8>) -> String {
         let mut rdr = Cursor::new(&data);
@@ -34,20 +34,21 @@ impl GatewayProtocol {
         let opcode = full_opcode & 0x1f;
         let channel = full_opcode >> 5;
 
-        match opcode {
+        let parsed = match opcode {
             0x01 => self.parse_login_request(rdr),
             0x02 => self.parse_login_reply(rdr),
-            0x03 => r#"{"name":"Logout"}"#.to_string(),
-            0x04 => r#"{"name":"ForceDisconnect"}"#.to_string(),
-            0x05 => self.parse_tunnel_data(data),
-            0x06 => self.parse_tunnel_data(data),
-            0x07 => self.parse_channel_is_routable(rdr),
-            0x08 => self.parse_channel_is_not_routable(rdr),
-            _ => format!(
+            0x03 => Ok(r#"{"name":"Logout"}"#.to_string()),
+            0x04 => Ok(r#"{"name":"ForceDisconnect"}"#.to_string()),
+            0x05 => self.parse_tunnel_data(&data),
+            0x06 => self.parse_tunnel_data(&data),
+            0x07 => Ok(self.parse_channel_is_routable(rdr)),
+            0x08 => Ok(self.parse_channel_is_not_routable(rdr)),
+            _ => Ok(format!(
                 r#"{{"name":"Unknown","channel":{},"raw":{:?}}}"#,
                 channel, data
-            ),
-        }
+            )),
+        };
+        parsed.unwrap_or_else(|error| error.to_json(&data))
     }
 
     pub fn pack_login_request_packet(
@@ -73,73 +74,65 @@ impl GatewayProtocol {
     pub fn pack_tunnel_data_packet_for_server(&mut self, data: Vec<u8>, channel: u8) -> Vec<u8> {
         self._pack_tunnel_data_packet(0x06, data, channel)
     }
-    fn _pack_tunnel_data_packet(
-        &mut self,
-        base_opcode: u8,
-        mut data: Vec<u8>,
-        channel: u8,
-    ) -> Vec<u8> {
-        let opcode = base_opcode | channel << 5;
-        self.wtr.clear();
-        self.wtr.write_u8(opcode).unwrap_or_default();
-        self.wtr.append(&mut data);
-        self.wtr.clone()
+    fn _pack_tunnel_data_packet(&mut self, base_opcode: u8, data: Vec<u8>, channel: u8) -> Vec<u8> {
+        TunnelDataRef {
+            for_client: base_opcode == 0x05,
+            channel,
+            data: &data,
+        }
+        .pack_to_vec()
     }
     pub fn pack_channel_is_routable_packet(&mut self) -> Vec<u8> {
-        let opcode = 0x07;
-        self.wtr.clear();
-        self.wtr.write_u8(opcode).unwrap_or_default();
-        self.wtr.clone()
+        ChannelIsRoutablePacket { is_routable: true }.pack_to_vec()
     }
     pub fn pack_channel_is_not_routable_packet(&mut self) -> Vec<u8> {
-        let opcode = 0x08;
-        self.wtr.clear();
-        self.wtr.write_u8(opcode).unwrap_or_default();
-        self.wtr.clone()
+        ChannelIsRoutablePacket { is_routable: false }.pack_to_vec()
     }
 }
 
 impl GatewayProtocol {
-    fn parse_login_request(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        let character_id = rdr.read_u64::<LittleEndian>().unwrap_or_default();
-        let raw_data = rdr.clone().into_inner();
-        let ticket_data_pos = rdr.position();
-        let ticket_data_len = rdr.read_u32::<LittleEndian>().unwrap_or_default();
-        let ticket = read_prefixed_string_le(raw_data, ticket_data_pos as usize, ticket_data_len);
-        rdr.set_position(ticket_data_pos + ticket_data_len as u64 + 4);
-        let client_protocol_data_pos = rdr.position();
-        let client_protocol_data_len = rdr.read_u32::<LittleEndian>().unwrap_or_default();
-        let client_protocol = read_prefixed_string_le(
-            raw_data,
-            client_protocol_data_pos as usize,
-            client_protocol_data_len,
-        );
-        rdr.set_position(client_protocol_data_pos + client_protocol_data_len as u64 + 4);
-        let client_build_data_pos = rdr.position();
-        let client_build_data_len = rdr.read_u32::<LittleEndian>().unwrap_or_default();
-        let client_build = read_prefixed_string_le(
-            raw_data,
-            client_build_data_pos as usize,
-            client_build_data_len,
-        );
-        format!(
+    fn parse_login_request(
+        &mut self,
+        mut rdr: Cursor<&std::vec::Vec<u8>>,
+    ) -> Result<String, ProtocolError> {
+        let character_id =
+            rdr.read_u64::<LittleEndian>()
+                .map_err(|_| ProtocolError::Truncated {
+                    offset: 1,
+                    length: 8,
+                })?;
+        let ticket = read_prefixed_string(&mut rdr)?;
+        let client_protocol = read_prefixed_string(&mut rdr)?;
+        let client_build = read_prefixed_string(&mut rdr)?;
+        Ok(format!(
             r#"{{"name":"LoginRequest","character_id":"0x{:x}","ticket":"{}","client_protocol":"{}","client_build":"{}"}}"#,
             character_id, ticket, client_protocol, client_build
-        )
+        ))
     }
-    fn parse_login_reply(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        let logged_in: bool = rdr.read_u8().unwrap_or_default() != 0; // convert to bool
-        format!(r#"{{"name":"LoginReply","logged_in":{}}}"#, logged_in)
+    fn parse_login_reply(
+        &mut self,
+        mut rdr: Cursor<&std::vec::Vec<u8>>,
+    ) -> Result<String, ProtocolError> {
+        let logged_in: bool = rdr.read_u8().map_err(|_| ProtocolError::Truncated {
+            offset: 1,
+            length: 1,
+        })? != 0; // convert to bool
+        Ok(format!(
+            r#"{{"name":"LoginReply","logged_in":{}}}"#,
+            logged_in
+        ))
     }
-    fn parse_tunnel_data(&mut self, mut data: std::vec::Vec<u8>) -> String {
-        let channel = data.remove(0) >> 5;
-        let tunnel_data = data;
+    fn parse_tunnel_data(&mut self, data: &[u8]) -> Result<String, ProtocolError> {
+        let (full_opcode, tunnel_data) = data.split_first().ok_or(ProtocolError::Truncated {
+            offset: 0,
+            length: 1,
+        })?;
         let packet = TunnelPacket {
             name: "TunnelPacket",
-            channel,
-            tunnel_data,
+            channel: full_opcode >> 5,
+            tunnel_data: tunnel_data.to_vec(),
         };
-        serde_json::to_string(&packet).unwrap_or_default()
+        Ok(serde_json::to_string(&packet)?)
     }
     fn parse_channel_is_routable(&mut self, mut _rdr: Cursor<&std::vec::Vec<u8>>) -> String {
         format!(r#"{{"name":"ChannelIsRoutable","raw":"{:?}"}}"#, _rdr)
@@ -149,37 +142,31 @@ impl GatewayProtocol {
     }
 
     pub fn pack_login_request_object(&mut self, packet: LoginRequestPacket) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr.write_u8(0x01).unwrap_or_default();
-        self.wtr
-            .write_u64::<LittleEndian>(packet.character_id)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<LittleEndian>(packet.ticket.len() as u32)
-            .unwrap_or_default();
-        self.wtr.append(&mut packet.ticket.as_bytes().to_vec());
-        self.wtr
-            .write_u32::<LittleEndian>(packet.client_protocol.len() as u32)
-            .unwrap_or_default();
-        self.wtr
-            .append(&mut packet.client_protocol.as_bytes().to_vec());
-        self.wtr
-            .write_u32::<LittleEndian>(packet.client_build.len() as u32)
-            .unwrap_or_default();
-        self.wtr
-            .append(&mut packet.client_build.as_bytes().to_vec());
-        self.wtr.clone()
+        packet.pack_to_vec()
     }
 
     pub fn pack_login_reply_object(&mut self, packet: LoginReplyPacket) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr.write_u8(0x02).unwrap_or_default();
-        self.wtr
-            .write_u8(packet.logged_in as u8)
-            .unwrap_or_default();
-        self.wtr.clone()
+        packet.pack_to_vec()
     }
 }
+
+fn read_prefixed_string(rdr: &mut Cursor<&std::vec::Vec<u8>>) -> Result<String, ProtocolError> {
+    let offset = rdr.position() as usize;
+    let length = rdr
+        .read_u32::<LittleEndian>()
+        .map_err(|_| ProtocolError::Truncated { offset, length: 4 })?;
+    let raw_data = rdr.get_ref();
+    if offset + 4 + length as usize > raw_data.len() {
+        return Err(ProtocolError::Truncated {
+            offset: offset + 4,
+            length: length as usize,
+        });
+    }
+    let string = read_prefixed_string_le(raw_data, offset, length);
+    rdr.set_position((offset + 4 + length as usize) as u64);
+    Ok(string)
+}
+
 #[cfg(test)]
 mod tests {
     use rand::random;
@@ -201,6 +188,20 @@ mod tests {
         assert_eq!(data_parsed, succesful_data)
     }
     #[test]
+    fn login_request_parse_truncated_test() {
+        let mut gatewayprotocol_class = super::GatewayProtocol::initialize();
+        let data_to_parse: [u8; 16] = [
+            1, 244, 221, 253, 245, 153, 56, 150, 124, 5, 0, 0, 0, 105, 116, 115,
+        ];
+        let data_parsed: serde_json::Value =
+            serde_json::from_str(&gatewayprotocol_class.parse(data_to_parse.to_vec()))
+                .unwrap_or_default();
+        let succesfull_data_string = r#"{"name":"Error","error":"truncated","offset":13,"length":5,"raw":[1, 244, 221, 253, 245, 153, 56, 150, 124, 5, 0, 0, 0, 105, 116, 115]}"#;
+        let succesful_data: serde_json::Value =
+            serde_json::from_str(succesfull_data_string).unwrap_or_default();
+        assert_eq!(data_parsed, succesful_data)
+    }
+    #[test]
     fn login_reply_parse_test() {
         let mut gatewayprotocol_class = super::GatewayProtocol::initialize();
         let data_to_parse: [u8; 2] = [2, 1];
@@ -213,6 +214,27 @@ mod tests {
         assert_eq!(data_parsed, succesful_data)
     }
     #[test]
+    fn login_reply_parse_truncated_test() {
+        let mut gatewayprotocol_class = super::GatewayProtocol::initialize();
+        let data = vec![2];
+        let mut rdr = std::io::Cursor::new(&data);
+        rdr.set_position(1);
+        assert_eq!(
+            gatewayprotocol_class.parse_login_reply(rdr),
+            Err(super::ProtocolError::Truncated {
+                offset: 1,
+                length: 1
+            })
+        );
+        assert_eq!(
+            gatewayprotocol_class.parse_tunnel_data(&[]),
+            Err(super::ProtocolError::Truncated {
+                offset: 0,
+                length: 1
+            })
+        )
+    }
+    #[test]
     fn tunnel_data_parse_test() {
         let mut gatewayprotocol_class = super::GatewayProtocol::initialize();
         let data_to_parse: [u8; 32] = [
@@ -237,6 +259,18 @@ mod tests {
         assert_eq!(data_pack, [5, 68, 82, 37, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0])
     }
     #[test]
+    fn tunnel_data_pack_into_slice_test() {
+        use super::super::packing::PackInto;
+        let tunnel_data = super::TunnelDataRef {
+            for_client: false,
+            channel: 1,
+            data: &[68, 82],
+        };
+        let mut buffer = [0; 4];
+        let length = tunnel_data.pack_into_slice(&mut buffer).unwrap();
+        assert_eq!(buffer[..length], [38, 68, 82])
+    }
+    #[test]
     fn tunnel_data_pack_channel_1_test() {
         let mut gatewayprotocol_class = super::GatewayProtocol::initialize();
         let tunnel_data_to_pack = [68, 82, 37, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0];
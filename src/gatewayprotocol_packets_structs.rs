@@ -1,4 +1,7 @@
+use super::packing::PackInto;
+use byteorder::{LittleEndian, WriteBytesExt};
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LoginRequestPacket {
     pub character_id: u64,
@@ -24,3 +27,59 @@ pub struct TunnelPacket {
     pub channel: u8,
     pub tunnel_data: Vec<u8>,
 }
+
+// tunnel data borrowing its payload, for_client picks the 0x05 opcode over 0x06
+pub struct TunnelDataRef<'a> {
+    pub for_client: bool,
+    pub channel: u8,
+    pub data: &'a [u8],
+}
+
+impl PackInto for LoginRequestPacket {
+    fn packed_len(&self) -> usize {
+        21 + self.ticket.len() + self.client_protocol.len() + self.client_build.len()
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u8(0x01)?;
+        wtr.write_u64::<LittleEndian>(self.character_id)?;
+        for field in [&self.ticket, &self.client_protocol, &self.client_build] {
+            wtr.write_u32::<LittleEndian>(field.len() as u32)?;
+            wtr.write_all(field.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl PackInto for LoginReplyPacket {
+    fn packed_len(&self) -> usize {
+        2
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u8(0x02)?;
+        wtr.write_u8(self.logged_in as u8)
+    }
+}
+
+impl PackInto for ChannelIsRoutablePacket {
+    fn packed_len(&self) -> usize {
+        1
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u8(if self.is_routable { 0x07 } else { 0x08 })
+    }
+}
+
+impl PackInto for TunnelDataRef<'_> {
+    fn packed_len(&self) -> usize {
+        1 + self.data.len()
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        let base_opcode = if self.for_client { 0x05 } else { 0x06 };
+        wtr.write_u8(base_opcode | self.channel << 5)?;
+        wtr.write_all(self.data)
+    }
+}
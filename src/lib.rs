@@ -10,14 +10,34 @@ pub mod gatewayprotocol_packets_structs;
 pub mod jenkins;
 pub mod lib_utils;
 #[cfg(feature = "protocols")]
+pub mod packing;
+#[cfg(feature = "protocols")]
 pub mod protocol_errors;
 #[cfg(feature = "rc4")]
 pub mod rc4;
 #[cfg(feature = "soeprotocol")]
 pub mod soeprotocol;
 #[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_batcher;
+#[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_fragments;
+#[cfg(feature = "soeprotocol")]
 pub mod soeprotocol_functions;
 #[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_net_status;
+#[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_packets_refs;
+#[cfg(feature = "soeprotocol")]
 pub mod soeprotocol_packets_structs;
+#[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_reliable;
+#[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_sequence;
+#[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_session;
+#[cfg(feature = "soeprotocol")]
+pub mod soeprotocol_session_manager;
+#[cfg(feature = "transport")]
+pub mod soeprotocol_transport;
 #[cfg(feature = "game-utils")]
 pub mod utils;
@@ -29,11 +29,5 @@ pub fn read_prefixed_string_le(buffer: &[u8], offset: usize, length: u32) -> Str
     }
 }
 pub fn sat(x: f32) -> f32 {
-    if x < 0.0 {
-        0.0
-    } else if x > 1.0 {
-        1.0
-    } else {
-        x
-    }
+    x.clamp(0.0, 1.0)
 }
@@ -0,0 +1,55 @@
+use super::protocol_errors::ProtocolError;
+use std::io::{Cursor, Write};
+
+// packets that can be written straight into a caller provided buffer
+pub trait PackInto {
+    // exact number of bytes written by write_to
+    fn packed_len(&self) -> usize;
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()>;
+
+    // append the packet to wtr, nothing is allocated if wtr already has the capacity
+    fn pack_into(&self, wtr: &mut Vec<u8>) {
+        wtr.reserve(self.packed_len());
+        // writing to a vec can't fail
+        self.write_to(wtr).unwrap_or_default();
+    }
+
+    // write the packet at the start of buffer and return its length
+    fn pack_into_slice(&self, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        let needed = self.packed_len();
+        let capacity = buffer.len();
+        if needed > capacity {
+            return Err(ProtocolError::BufferTooSmall { needed, capacity });
+        }
+        let mut wtr = Cursor::new(buffer);
+        self.write_to(&mut wtr).map_err(|error| {
+            error
+                .into_inner()
+                .and_then(|inner| inner.downcast::<ProtocolError>().ok())
+                .map_or(
+                    ProtocolError::BufferTooSmall { needed, capacity },
+                    |error| *error,
+                )
+        })?;
+        Ok(wtr.position() as usize)
+    }
+
+    fn pack_to_vec(&self) -> Vec<u8> {
+        let mut wtr = Vec::with_capacity(self.packed_len());
+        self.pack_into(&mut wtr);
+        wtr
+    }
+}
+
+// same encoding as u8_from_str_nul_utf8_unchecked
+pub fn write_str_nul_utf8_unchecked<W: Write>(wtr: &mut W, data: &str) -> std::io::Result<()> {
+    for c in data.chars() {
+        wtr.write_all(&[c as u8])?;
+    }
+    wtr.write_all(&[0])
+}
+
+pub fn str_nul_utf8_unchecked_len(data: &str) -> usize {
+    data.chars().count() + 1
+}
@@ -1,45 +1,200 @@
-use std::io::Cursor;
+use serde_json::{json, Value};
+use std::fmt;
 
-use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::{JsError, JsValue};
 
-pub fn gen_size_error_json(rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-    format!(
-        r#"{{"name":"Error","error":"size","size":{},"raw":{:?}}}"#,
-        rdr.get_ref().len(),
-        rdr.get_ref().to_vec()
-    )
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    Size {
+        size: usize,
+    },
+    Crc {
+        expected: u32,
+        given: u32,
+    },
+    Corruption {
+        offset: u64,
+        length: u32,
+        data_end: u64,
+    },
+    Deserialize(String),
+    Truncated {
+        offset: usize,
+        length: usize,
+    },
+    FragmentOverrun {
+        expected: usize,
+        received: usize,
+    },
+    Sequence {
+        expected: u16,
+        given: u16,
+    },
+    UnexpectedPacket {
+        state: String,
+        packet: String,
+    },
+    Handshake(String),
+    Compression(String),
+    BufferTooSmall {
+        needed: usize,
+        capacity: usize,
+    },
+    Channel {
+        channel: u8,
+    },
 }
 
-pub fn gen_crc_error_json(vec: &Vec<u8>, expected_crc: u16, given_crc: u16) -> String {
-    format!(
-        r#"{{"name":"Error","error":"crc","expected_crc":{},"given_crc":{},"raw":{:?}}}"#,
-        expected_crc, given_crc, vec
-    )
+impl ProtocolError {
+    // json shape expected by the js side when a parse fails
+    pub fn to_json(&self, raw: &[u8]) -> String {
+        let fields = match self {
+            ProtocolError::Size { size } => json!({"error": "size", "size": size}),
+            ProtocolError::Crc { expected, given } => {
+                json!({"error": "crc", "expected_crc": expected, "given_crc": given})
+            }
+            ProtocolError::Corruption {
+                offset,
+                length,
+                data_end,
+            } => json!({
+                "error": "corruption",
+                "subpacket_length": length,
+                "data_end": data_end,
+                "position": offset,
+            }),
+            ProtocolError::Deserialize(message) => {
+                json!({"error": "deserialize", "message": message})
+            }
+            ProtocolError::Truncated { offset, length } => {
+                json!({"error": "truncated", "offset": offset, "length": length})
+            }
+            ProtocolError::FragmentOverrun { expected, received } => json!({
+                "error": "fragment_overrun",
+                "expected": expected,
+                "received": received,
+            }),
+            ProtocolError::Sequence { expected, given } => {
+                json!({"error": "sequence", "expected": expected, "given": given})
+            }
+            ProtocolError::UnexpectedPacket { state, packet } => json!({
+                "error": "unexpected_packet",
+                "state": state,
+                "packet": packet,
+            }),
+            ProtocolError::Handshake(message) => json!({"error": "handshake", "message": message}),
+            ProtocolError::Compression(message) => {
+                json!({"error": "compression", "message": message})
+            }
+            ProtocolError::BufferTooSmall { needed, capacity } => json!({
+                "error": "buffer_too_small",
+                "needed": needed,
+                "capacity": capacity,
+            }),
+            ProtocolError::Channel { channel } => json!({"error": "channel", "channel": channel}),
+        };
+        let mut error = json!({"name": "Error", "raw": raw});
+        if let (Some(error), Value::Object(fields)) = (error.as_object_mut(), fields) {
+            error.extend(fields);
+        }
+        error.to_string()
+    }
 }
 
-pub fn gen_corruption_error_json(
-    rdr: Cursor<&std::vec::Vec<u8>>,
-    subpacket_length: u32,
-    data_end: u64,
-) -> String {
-    format!(
-        r#"{{"name":"Error","error":"corruption","subpacket_length":{},"data_end":{},"position":{},"raw":{:?}}}"#,
-        subpacket_length,
-        data_end,
-        rdr.position() as usize,
-        rdr.get_ref().to_vec()
-    )
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Size { size } => write!(f, "invalid packet size {}", size),
+            ProtocolError::Crc { expected, given } => {
+                write!(f, "crc mismatch expected {} given {}", expected, given)
+            }
+            ProtocolError::Corruption {
+                offset,
+                length,
+                data_end,
+            } => write!(
+                f,
+                "corrupted sub packet of length {} at {} (data end {})",
+                length, offset, data_end
+            ),
+            ProtocolError::Deserialize(message) => write!(f, "deserializing error: {}", message),
+            ProtocolError::Truncated { offset, length } => {
+                write!(f, "truncated packet reading {} bytes at {}", length, offset)
+            }
+            ProtocolError::FragmentOverrun { expected, received } => write!(
+                f,
+                "fragments overrun total length {} with {} bytes",
+                expected, received
+            ),
+            ProtocolError::Sequence { expected, given } => {
+                write!(f, "unexpected sequence {} expected {}", given, expected)
+            }
+            ProtocolError::UnexpectedPacket { state, packet } => {
+                write!(f, "unexpected {} packet in {} state", packet, state)
+            }
+            ProtocolError::Handshake(message) => write!(f, "handshake error: {}", message),
+            ProtocolError::Compression(message) => write!(f, "compression error: {}", message),
+            ProtocolError::BufferTooSmall { needed, capacity } => write!(
+                f,
+                "packing {} bytes into a buffer of {} bytes",
+                needed, capacity
+            ),
+            ProtocolError::Channel { channel } => write!(f, "invalid reliable channel {}", channel),
+        }
+    }
 }
 
-#[wasm_bindgen]
-pub enum ErrorType {
-    Deserializing = 0x99,
+impl std::error::Error for ProtocolError {}
+
+// packers write through io, the error is recovered by pack_into_slice
+impl From<ProtocolError> for std::io::Error {
+    fn from(error: ProtocolError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, error)
+    }
+}
+
+impl From<serde_json::Error> for ProtocolError {
+    fn from(error: serde_json::Error) -> Self {
+        ProtocolError::Deserialize(error.to_string())
+    }
+}
+
+// thrown as a js Error by the wasm bindings
+impl From<ProtocolError> for JsValue {
+    fn from(error: ProtocolError) -> Self {
+        JsError::from(error).into()
+    }
 }
 
-pub fn gen_deserializing_error_json(error: serde_json::Error) -> Vec<u8> {
-    let error_string = format!("{}", error);
-    // create a vec starting with the error type and then the error stringify
-    let mut error_vec: Vec<u8> = vec![0x00, ErrorType::Deserializing as u8];
-    error_vec.append(&mut error_string.as_bytes().to_vec());
-    error_vec
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn protocol_error_json_test() {
+        let error = super::ProtocolError::Crc {
+            expected: 1,
+            given: 2,
+        };
+        let parsed: serde_json::Value = serde_json::from_str(&error.to_json(&[0, 21])).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(
+            r#"{"name":"Error","error":"crc","expected_crc":1,"given_crc":2,"raw":[0, 21]}"#,
+        )
+        .unwrap();
+        assert_eq!(parsed, expected)
+    }
+
+    #[test]
+    fn protocol_error_json_escape_test() {
+        let error = super::ProtocolError::Handshake("bad \u{1b} 'protocol' \"name\"".to_owned());
+        let parsed: serde_json::Value = serde_json::from_str(&error.to_json(&[])).unwrap();
+        assert_eq!(parsed["message"], "bad \u{1b} 'protocol' \"name\"")
+    }
+
+    #[test]
+    fn protocol_error_from_serde_test() {
+        let error: super::ProtocolError = serde_json::from_str::<u32>("{")
+            .map_err(super::ProtocolError::from)
+            .unwrap_err();
+        assert!(matches!(error, super::ProtocolError::Deserialize(_)))
+    }
 }
@@ -33,7 +33,7 @@ impl RC4 {
 
     #[wasm_bindgen]
     pub fn encrypt(&mut self, mut data: Vec<u8>) -> Vec<u8> {
-        for k in 0..data.len() {
+        for byte in data.iter_mut() {
             self.i = self.i.wrapping_add(1);
             let i = self.i as usize;
             self.j = self.j.wrapping_add(self.s[i]);
@@ -42,7 +42,7 @@ impl RC4 {
             self.s.swap(i, j);
             let si = self.s[i];
             let sj = self.s[j];
-            data[k] ^= self.s[si.wrapping_add(sj) as usize];
+            *byte ^= self.s[si.wrapping_add(sj) as usize];
         }
 
         data
@@ -1,15 +1,12 @@
-use super::protocol_errors::{
-    gen_corruption_error_json, gen_crc_error_json, gen_deserializing_error_json,
-    gen_size_error_json,
-};
+use super::crc::{append_crc_with_length, DEFAULT_CRC_LENGTH, MAX_CRC_LENGTH};
+use super::protocol_errors::ProtocolError;
 
+use super::packing::PackInto;
+use super::soeprotocol_fragments::{max_reliable_data_size, split_reliable_data};
 use super::soeprotocol_functions::*;
-use super::{
-    crc::crc32,
-    lib_utils::{str_from_u8_nul_utf8_checked, u8_from_str_nul_utf8_unchecked},
-    soeprotocol_packets_structs::*,
-};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::soeprotocol_packets_refs::{parse_packet_ref, SoePacketRef};
+use super::soeprotocol_packets_structs::*;
+use byteorder::{BigEndian, ReadBytesExt};
 use gloo_utils::format::JsValueSerdeExt;
 use std::io::Cursor;
 use wasm_bindgen::prelude::*;
@@ -18,10 +15,14 @@ use wasm_bindgen::prelude::*;
 pub struct Soeprotocol {
     use_crc: bool,
     crc_seed: u32,
-    wtr: Vec<u8>,
+    crc_length: u8,
+    // payloads bigger than the threshold are compressed when set
+    compression_threshold: Option<usize>,
+    encrypt_method: EncryptMethod,
 }
 
 #[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EncryptMethod {
     EncryptMethodNone = 0x0,
     EncryptMethodUserSupplied = 0x1,
@@ -30,6 +31,93 @@ pub enum EncryptMethod {
     EncryptMethodXor = 0x4,
 }
 
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    DisconnectReasonIcmpError = 0,
+    DisconnectReasonTimeout = 1,
+    DisconnectReasonNone = 2,
+    DisconnectReasonOtherSideTerminated = 3,
+    DisconnectReasonManagerDeleted = 4,
+    DisconnectReasonConnectFail = 5,
+    DisconnectReasonApplication = 6,
+    DisconnectReasonUnreachableConnection = 7,
+    DisconnectReasonUnacknowledgedTimeout = 8,
+    DisconnectReasonNewConnectionAttempt = 9,
+    DisconnectReasonConnectionRefused = 10,
+    DisconnectReasonConnectError = 11,
+    DisconnectReasonConnectingToSelf = 12,
+    DisconnectReasonReliableOverflow = 13,
+    DisconnectReasonApplicationReleased = 14,
+    DisconnectReasonCorruptPacket = 15,
+    DisconnectReasonProtocolMismatch = 16,
+}
+
+impl DisconnectReason {
+    pub fn from_code(code: u16) -> Option<DisconnectReason> {
+        match code {
+            0 => Some(DisconnectReason::DisconnectReasonIcmpError),
+            1 => Some(DisconnectReason::DisconnectReasonTimeout),
+            2 => Some(DisconnectReason::DisconnectReasonNone),
+            3 => Some(DisconnectReason::DisconnectReasonOtherSideTerminated),
+            4 => Some(DisconnectReason::DisconnectReasonManagerDeleted),
+            5 => Some(DisconnectReason::DisconnectReasonConnectFail),
+            6 => Some(DisconnectReason::DisconnectReasonApplication),
+            7 => Some(DisconnectReason::DisconnectReasonUnreachableConnection),
+            8 => Some(DisconnectReason::DisconnectReasonUnacknowledgedTimeout),
+            9 => Some(DisconnectReason::DisconnectReasonNewConnectionAttempt),
+            10 => Some(DisconnectReason::DisconnectReasonConnectionRefused),
+            11 => Some(DisconnectReason::DisconnectReasonConnectError),
+            12 => Some(DisconnectReason::DisconnectReasonConnectingToSelf),
+            13 => Some(DisconnectReason::DisconnectReasonReliableOverflow),
+            14 => Some(DisconnectReason::DisconnectReasonApplicationReleased),
+            15 => Some(DisconnectReason::DisconnectReasonCorruptPacket),
+            16 => Some(DisconnectReason::DisconnectReasonProtocolMismatch),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DisconnectReason::DisconnectReasonIcmpError => "DisconnectReasonIcmpError",
+            DisconnectReason::DisconnectReasonTimeout => "DisconnectReasonTimeout",
+            DisconnectReason::DisconnectReasonNone => "DisconnectReasonNone",
+            DisconnectReason::DisconnectReasonOtherSideTerminated => {
+                "DisconnectReasonOtherSideTerminated"
+            }
+            DisconnectReason::DisconnectReasonManagerDeleted => "DisconnectReasonManagerDeleted",
+            DisconnectReason::DisconnectReasonConnectFail => "DisconnectReasonConnectFail",
+            DisconnectReason::DisconnectReasonApplication => "DisconnectReasonApplication",
+            DisconnectReason::DisconnectReasonUnreachableConnection => {
+                "DisconnectReasonUnreachableConnection"
+            }
+            DisconnectReason::DisconnectReasonUnacknowledgedTimeout => {
+                "DisconnectReasonUnacknowledgedTimeout"
+            }
+            DisconnectReason::DisconnectReasonNewConnectionAttempt => {
+                "DisconnectReasonNewConnectionAttempt"
+            }
+            DisconnectReason::DisconnectReasonConnectionRefused => {
+                "DisconnectReasonConnectionRefused"
+            }
+            DisconnectReason::DisconnectReasonConnectError => "DisconnectReasonConnectError",
+            DisconnectReason::DisconnectReasonConnectingToSelf => {
+                "DisconnectReasonConnectingToSelf"
+            }
+            DisconnectReason::DisconnectReasonReliableOverflow => {
+                "DisconnectReasonReliableOverflow"
+            }
+            DisconnectReason::DisconnectReasonApplicationReleased => {
+                "DisconnectReasonApplicationReleased"
+            }
+            DisconnectReason::DisconnectReasonCorruptPacket => "DisconnectReasonCorruptPacket",
+            DisconnectReason::DisconnectReasonProtocolMismatch => {
+                "DisconnectReasonProtocolMismatch"
+            }
+        }
+    }
+}
+
 #[wasm_bindgen]
 #[derive(Debug)]
 pub enum SoeOpcode {
@@ -50,10 +138,17 @@ pub enum SoeOpcode {
     Unknown = 0x00,
 }
 
-impl Soeprotocol {
-    // rust only
-    pub fn get_opcode(&mut self, rdr: &mut Cursor<&Vec<u8>>) -> SoeOpcode {
-        let opcode = rdr.read_u16::<BigEndian>().unwrap_or_default();
+pub const RELIABLE_CHANNEL_COUNT: u8 = 4;
+
+pub fn check_channel(channel: u8) -> Result<u8, ProtocolError> {
+    if channel >= RELIABLE_CHANNEL_COUNT {
+        return Err(ProtocolError::Channel { channel });
+    }
+    Ok(channel)
+}
+
+impl SoeOpcode {
+    pub fn from_code(opcode: u16) -> SoeOpcode {
         match opcode {
             0x01 => SoeOpcode::SessionRequest,
             0x02 => SoeOpcode::SessionReply,
@@ -62,15 +157,131 @@ impl Soeprotocol {
             0x06 => SoeOpcode::Ping,
             0x07 => SoeOpcode::NetStatusRequest,
             0x08 => SoeOpcode::NetStatusReply,
-            0x09 => SoeOpcode::Data,
-            0x0d => SoeOpcode::DataFragment,
-            0x11 => SoeOpcode::OutOfOrder,
-            0x15 => SoeOpcode::Ack,
+            0x09..=0x0c => SoeOpcode::Data,
+            0x0d..=0x10 => SoeOpcode::DataFragment,
+            0x11..=0x14 => SoeOpcode::OutOfOrder,
+            0x15..=0x18 => SoeOpcode::Ack,
+            0x19 => SoeOpcode::Group,
             0x1B => SoeOpcode::Ordered,
             0x1D => SoeOpcode::FatalError,
             _ => SoeOpcode::Unknown,
         }
     }
+
+    // reliable opcodes come in ranges of four, one per channel
+    pub fn get_channel(opcode: u16) -> u8 {
+        match opcode {
+            0x09..=0x18 => ((opcode - 0x09) % RELIABLE_CHANNEL_COUNT as u16) as u8,
+            _ => 0,
+        }
+    }
+
+    // a bigger channel would overlap the next opcode range
+    pub fn with_channel(self, channel: u8) -> Result<u16, ProtocolError> {
+        check_channel(channel)?;
+        Ok(self as u16 + channel as u16)
+    }
+}
+
+impl Soeprotocol {
+    // rust only
+    pub fn get_opcode(&self, rdr: &mut Cursor<&[u8]>) -> SoeOpcode {
+        SoeOpcode::from_code(rdr.read_u16::<BigEndian>().unwrap_or_default())
+    }
+    pub fn parse_packet(&self, data: &[u8]) -> Result<SoePacket, ProtocolError> {
+        let mut scratch = vec![];
+        self.parse_packet_ref(data, &mut scratch)?.into_owned()
+    }
+
+    // borrow from the datagram, scratch only holds decrypted or inflated packets
+    pub fn parse_packet_ref<'a>(
+        &self,
+        data: &'a [u8],
+        scratch: &'a mut Vec<u8>,
+    ) -> Result<SoePacketRef<'a>, ProtocolError> {
+        let compressed = self.compression_threshold.is_some() && is_compressible(data);
+        let encrypted = self.is_encrypting(data);
+        if !compressed && !encrypted {
+            return parse_packet_ref(data, self.get_active_crc_length(), self.crc_seed);
+        }
+        *scratch = self.unwrap_packet(data, compressed, encrypted)?;
+        parse_packet_ref(scratch, 0, self.crc_seed)
+    }
+
+    // check the crc, decrypt then strip the compression flag, sub packets never go through this
+    fn unwrap_packet(
+        &self,
+        data: &[u8],
+        compressed: bool,
+        encrypted: bool,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let crc_length = self.get_active_crc_length();
+        let rdr = Cursor::new(data);
+        let min_size = if compressed { 3 } else { 2 };
+        if !check_min_size(&rdr, min_size, crc_length) {
+            return Err(ProtocolError::Size { size: data.len() });
+        }
+        let data_end = get_data_end(&rdr, crc_length) as usize;
+        if crc_length > 0 {
+            verify_crc(data, data_end, self.crc_seed, crc_length)?;
+        }
+        let mut unwrapped = data[..data_end].to_vec();
+        if encrypted {
+            decrypt_body(&mut unwrapped[2..], self.encrypt_method, self.crc_seed);
+        }
+        if compressed {
+            let mut inflated = unwrapped[..2].to_vec();
+            inflated.append(&mut inflate_payload(&unwrapped[2..])?);
+            unwrapped = inflated;
+        }
+        Ok(unwrapped)
+    }
+
+    // compress, encrypt then add the crc footer as configured
+    pub fn finalize_packet(&self, packet: &[u8]) -> Vec<u8> {
+        let mut finalized = match self.compression_threshold {
+            Some(threshold) if is_compressible(packet) => {
+                let mut finalized = packet[..2].to_vec();
+                finalized.append(&mut compress_payload(&packet[2..], threshold));
+                finalized
+            }
+            _ => packet.to_vec(),
+        };
+        if self.is_encrypting(packet) {
+            encrypt_body(&mut finalized[2..], self.encrypt_method, self.crc_seed);
+        }
+        if !is_plain_packet(packet) {
+            self.append_crc(&mut finalized);
+        }
+        finalized
+    }
+
+    // session packets are exchanged before any encryption is negotiated
+    fn is_encrypting(&self, packet: &[u8]) -> bool {
+        self.encrypt_method != EncryptMethod::EncryptMethodNone
+            && packet.len() >= 2
+            && !is_plain_packet(packet)
+    }
+
+    // number of crc bytes expected at the end of packets
+    fn get_active_crc_length(&self) -> u8 {
+        if self.use_crc {
+            self.crc_length
+        } else {
+            0
+        }
+    }
+
+    // most bytes finalize_packet adds to a packet, the crc footer and the compression flag
+    pub fn get_finalize_overhead(&self) -> usize {
+        self.get_active_crc_length() as usize + self.is_using_compression() as usize
+    }
+
+    // append the crc footer when crc is in use
+    pub fn append_crc(&self, data: &mut Vec<u8>) {
+        append_crc_with_length(data, self.crc_seed, self.get_active_crc_length());
+    }
+
     pub fn get_session_request_object(
         &mut self,
         packet_string: String,
@@ -79,27 +290,11 @@ impl Soeprotocol {
     }
 
     pub fn pack_ordered_object(&mut self, packet: DataPacket) -> Vec<u8> {
-        self._pack_data_object(SoeOpcode::Ordered as u16, packet)
+        SoePacket::Ordered(packet).pack_to_vec()
     }
 
     pub fn pack_session_request_object(&mut self, packet: SessionRequestPacket) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr
-            .write_u16::<BigEndian>(SoeOpcode::SessionRequest as u16)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.protocol_version)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.session_id)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.udp_length)
-            .unwrap_or_default();
-        self.wtr.append(&mut u8_from_str_nul_utf8_unchecked(
-            packet.get_protocol().as_str(),
-        ));
-        self.wtr.clone()
+        packet.pack_to_vec()
     }
 
     pub fn get_session_reply_object(
@@ -110,25 +305,29 @@ impl Soeprotocol {
     }
 
     pub fn pack_session_reply_object(&mut self, packet: SessionReplyPacket) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr
-            .write_u16::<BigEndian>(SoeOpcode::SessionReply as u16)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.session_id)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.crc_seed)
-            .unwrap_or_default();
-        self.wtr.write_u8(packet.crc_length).unwrap_or_default();
-        self.wtr
-            .write_u16::<BigEndian>(packet.encrypt_method)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.udp_length)
-            .unwrap_or_default();
-        self.wtr.write_u32::<BigEndian>(3).unwrap_or_default();
-        self.wtr.clone()
+        packet.pack_to_vec()
+    }
+
+    pub fn get_fatal_error_object(
+        &mut self,
+        packet_string: String,
+    ) -> Result<FatalErrorPacket, serde_json::Error> {
+        serde_json::from_str(&packet_string)
+    }
+
+    pub fn pack_fatal_error_object(&mut self, packet: FatalErrorPacket) -> Vec<u8> {
+        packet.pack_to_vec()
+    }
+
+    pub fn get_disconnect_object(
+        &mut self,
+        packet_string: String,
+    ) -> Result<DisconnectPacket, serde_json::Error> {
+        serde_json::from_str(&packet_string)
+    }
+
+    pub fn pack_disconnect_object(&mut self, packet: DisconnectPacket) -> Vec<u8> {
+        packet.pack_to_vec()
     }
 
     pub fn get_net_status_request_object(
@@ -139,81 +338,18 @@ impl Soeprotocol {
     }
 
     pub fn pack_net_status_request_object(&mut self, packet: NetStatusRequestPacket) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr
-            .write_u16::<BigEndian>(SoeOpcode::NetStatusRequest as u16)
-            .unwrap_or_default();
-        self.wtr
-            .write_u16::<BigEndian>(packet.client_tick_count)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.last_client_update)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.average_update)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.shortest_update)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.longest_update)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.last_server_update)
-            .unwrap_or_default();
-        self.wtr
-            .write_u64::<BigEndian>(packet.packets_sent)
-            .unwrap_or_default();
-        self.wtr
-            .write_u64::<BigEndian>(packet.packets_received)
-            .unwrap_or_default();
-        self.wtr
-            .write_u16::<BigEndian>(packet.unknown_field)
-            .unwrap_or_default();
-        self.wtr.clone()
-    }
-
-    pub fn get_net_status_reply_object(&mut self, packet_string: String) -> NetStatusReplyPacket {
-        serde_json::from_str(&packet_string).unwrap_or({
-            NetStatusReplyPacket {
-                client_tick_count: 0,
-                server_tick_count: 0,
-                client_packet_sent: 0,
-                client_packet_received: 0,
-                server_packet_sent: 0,
-                server_packet_received: 0,
-                unknown_field: 0,
-            }
-        })
+        packet.pack_to_vec()
+    }
+
+    pub fn get_net_status_reply_object(
+        &mut self,
+        packet_string: String,
+    ) -> Result<NetStatusReplyPacket, serde_json::Error> {
+        serde_json::from_str(&packet_string)
     }
 
     pub fn pack_net_status_reply_object(&mut self, packet: NetStatusReplyPacket) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr
-            .write_u16::<BigEndian>(SoeOpcode::NetStatusReply as u16)
-            .unwrap_or_default();
-        self.wtr
-            .write_u16::<BigEndian>(packet.client_tick_count)
-            .unwrap_or_default();
-        self.wtr
-            .write_u32::<BigEndian>(packet.server_tick_count)
-            .unwrap_or_default();
-        self.wtr
-            .write_u64::<BigEndian>(packet.client_packet_sent)
-            .unwrap_or_default();
-        self.wtr
-            .write_u64::<BigEndian>(packet.client_packet_received)
-            .unwrap_or_default();
-        self.wtr
-            .write_u64::<BigEndian>(packet.server_packet_sent)
-            .unwrap_or_default();
-        self.wtr
-            .write_u64::<BigEndian>(packet.server_packet_received)
-            .unwrap_or_default();
-        self.wtr
-            .write_u16::<BigEndian>(packet.unknown_field)
-            .unwrap_or_default();
-        self.wtr.clone()
+        packet.pack_to_vec()
     }
 
     pub fn get_multi_object(
@@ -223,16 +359,12 @@ impl Soeprotocol {
         serde_json::from_str(&packet_string)
     }
 
-    pub fn group_packets(&mut self, opcode: u16, packets: &Vec<Vec<u8>>) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr.write_u16::<BigEndian>(opcode).unwrap_or_default();
-        for packet in packets {
-            write_data_length(&mut self.wtr, packet.len());
-            // FIXME: shitty clone
-            let mut packet = packet.clone();
-            self.wtr.append(&mut packet);
-        }
-        self.wtr.clone()
+    pub fn group_packets(&mut self, opcode: u16, packets: &[Vec<u8>]) -> Vec<u8> {
+        let sub_packets = packets.iter().map(Vec::as_slice);
+        let mut wtr = Vec::with_capacity(sub_packets_len(sub_packets.clone()));
+        // writing to a vec can't fail
+        write_sub_packets(&mut wtr, opcode, sub_packets).unwrap_or_default();
+        wtr
     }
 
     pub fn pack_group_object(&mut self, group_packet: SubBasePackets) -> Vec<u8> {
@@ -253,19 +385,47 @@ impl Soeprotocol {
         serde_json::from_str(&packet_string)
     }
 
-    fn _pack_data_object(&mut self, opcode: u16, mut packet: DataPacket) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr.write_u16::<BigEndian>(opcode).unwrap_or_default();
-        write_packet_data(&mut self.wtr, &mut packet);
-        self.wtr.clone()
-    }
-
     pub fn pack_data_object(&mut self, packet: DataPacket) -> Vec<u8> {
-        self._pack_data_object(0x09, packet)
+        SoePacket::Data(packet).pack_to_vec()
     }
 
     pub fn pack_fragment_data_object(&mut self, packet: DataPacket) -> Vec<u8> {
-        self._pack_data_object(0x0d, packet)
+        SoePacket::DataFragment(packet).pack_to_vec()
+    }
+
+    // pack a reliable payload as a single data packet or as fragments fitting in udp_length
+    pub fn pack_reliable_data(
+        &mut self,
+        data: &[u8],
+        channel: u8,
+        first_sequence: u16,
+        udp_length: u32,
+        crc_length: u8,
+    ) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        check_channel(channel)?;
+        // finalize_packet adds the compression flag byte when compression is enabled
+        let max_data_size = max_reliable_data_size(udp_length, crc_length)
+            .saturating_sub(self.is_using_compression() as usize);
+        if data.len() <= max_data_size {
+            let mut packet = DataPacket::new(data.to_vec(), first_sequence);
+            packet.set_channel(channel)?;
+            return Ok(vec![self.pack_data_object(packet)]);
+        }
+        // the first fragment must hold the total length and some data
+        if max_data_size <= 4 {
+            return Err(ProtocolError::Size {
+                size: udp_length as usize,
+            });
+        }
+        let mut sequence = first_sequence;
+        let mut packets = vec![];
+        for fragment in split_reliable_data(data, max_data_size) {
+            let mut packet = DataPacket::new(fragment, sequence);
+            packet.set_channel(channel)?;
+            packets.push(self.pack_fragment_data_object(packet));
+            sequence = sequence.wrapping_add(1);
+        }
+        Ok(packets)
     }
 
     pub fn get_ack_object(
@@ -275,21 +435,12 @@ impl Soeprotocol {
         serde_json::from_str(&packet_string)
     }
 
-    fn _pack_ack_object(&mut self, opcode: u16, sequence: u16) -> Vec<u8> {
-        self.wtr.clear();
-        self.wtr.write_u16::<BigEndian>(opcode).unwrap_or_default();
-        self.wtr
-            .write_u16::<BigEndian>(sequence)
-            .unwrap_or_default();
-        self.wtr.clone()
-    }
-
     pub fn pack_out_of_order_object(&mut self, packet: AckPacket) -> Vec<u8> {
-        self._pack_ack_object(0x11, packet.sequence)
+        SoePacket::OutOfOrder(packet).pack_to_vec()
     }
 
     pub fn pack_ack_object(&mut self, packet: AckPacket) -> Vec<u8> {
-        self._pack_ack_object(0x15, packet.sequence)
+        SoePacket::Ack(packet).pack_to_vec()
     }
 }
 #[wasm_bindgen]
@@ -300,17 +451,23 @@ impl Soeprotocol {
         Soeprotocol {
             use_crc,
             crc_seed,
-            wtr: vec![],
+            crc_length: DEFAULT_CRC_LENGTH,
+            compression_threshold: None,
+            encrypt_method: EncryptMethod::EncryptMethodNone,
         }
     }
-    pub fn pack(&mut self, packet_opcode: SoeOpcode, packet: String) -> Vec<u8> {
+    pub fn pack(
+        &mut self,
+        packet_opcode: SoeOpcode,
+        packet: String,
+    ) -> Result<Vec<u8>, ProtocolError> {
         match packet_opcode {
             SoeOpcode::SessionRequest => self.pack_session_request(packet),
             SoeOpcode::SessionReply => self.pack_session_reply(packet),
             SoeOpcode::MultiPacket => self.pack_multi(packet),
             SoeOpcode::Group => self.pack_group(packet),
-            SoeOpcode::Disconnect => vec![0, 5],
-            SoeOpcode::Ping => vec![0, 6],
+            SoeOpcode::Disconnect => self.pack_disconnect(packet),
+            SoeOpcode::Ping => Ok(vec![0, 6]),
             SoeOpcode::NetStatusRequest => self.pack_net_status_request(packet),
             SoeOpcode::NetStatusReply => self.pack_net_status_reply(packet),
             SoeOpcode::Data => self.pack_data(packet),
@@ -318,37 +475,69 @@ impl Soeprotocol {
             SoeOpcode::OutOfOrder => self.pack_out_of_order(packet),
             SoeOpcode::Ack => self.pack_ack(packet),
             SoeOpcode::Ordered => self.pack_ordered(packet),
-            SoeOpcode::FatalError => vec![],
-            SoeOpcode::Unknown => vec![],
+            SoeOpcode::FatalError => self.pack_fatal_error(packet),
+            SoeOpcode::Unknown => Ok(vec![]),
         }
     }
 
-    pub fn pack_ordered(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<DataPacket, serde_json::Error> = self.get_data_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_ordered_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_fatal_error(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: FatalErrorPacket = self.get_fatal_error_object(packet)?;
+        Ok(self.pack_fatal_error_object(packet_object))
+    }
+
+    pub fn pack_fatal_error_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: FatalErrorPacket = js_object.into_serde()?;
+        Ok(self.pack_fatal_error_object(packet))
+    }
+
+    // like disconnects, fatal errors are sent on their own
+    pub fn pack_fatal_error_packet(&mut self, error_code: u16, message: String) -> Vec<u8> {
+        let packet = self.pack_fatal_error_object(FatalErrorPacket::new(error_code, message));
+        self.finalize_packet(&packet)
+    }
+
+    pub fn pack_disconnect(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: DisconnectPacket = self.get_disconnect_object(packet)?;
+        Ok(self.pack_disconnect_object(packet_object))
+    }
+
+    pub fn pack_disconnect_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: DisconnectPacket = js_object.into_serde()?;
+        Ok(self.pack_disconnect_object(packet))
+    }
+
+    // disconnects are sent on their own so the packet is finalized right away
+    pub fn pack_disconnect_packet(&mut self, session_id: u32, reason: DisconnectReason) -> Vec<u8> {
+        let packet = self.pack_disconnect_object(DisconnectPacket::new(session_id, reason as u16));
+        self.finalize_packet(&packet)
+    }
+
+    pub fn pack_ordered(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: DataPacket = self.get_data_object(packet)?;
+        Ok(self.pack_ordered_object(packet_object))
     }
 
     pub fn pack_ordered_packet(&mut self, data: Vec<u8>, sequence: u16) -> Vec<u8> {
         self.pack_ordered_object(DataPacket::new(data, sequence))
     }
 
-    pub fn pack_session_request(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<SessionRequestPacket, serde_json::Error> =
-            self.get_session_request_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_session_request_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_session_request(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: SessionRequestPacket = self.get_session_request_object(packet)?;
+        Ok(self.pack_session_request_object(packet_object))
     }
 
-    pub fn pack_session_request_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: SessionRequestPacket = js_object.into_serde().unwrap();
-        self.pack_session_request_object(packet)
+    pub fn pack_session_request_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: SessionRequestPacket = js_object.into_serde()?;
+        Ok(self.pack_session_request_object(packet))
     }
 
     pub fn pack_session_request_packet(
@@ -363,19 +552,17 @@ impl Soeprotocol {
         ))
     }
 
-    pub fn pack_session_reply(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<SessionReplyPacket, serde_json::Error> =
-            self.get_session_reply_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_session_reply_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_session_reply(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: SessionReplyPacket = self.get_session_reply_object(packet)?;
+        Ok(self.pack_session_reply_object(packet_object))
     }
 
-    pub fn pack_session_reply_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: SessionReplyPacket = js_object.into_serde().unwrap();
-        self.pack_session_reply_object(packet)
+    pub fn pack_session_reply_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: SessionReplyPacket = js_object.into_serde()?;
+        Ok(self.pack_session_reply_object(packet))
     }
 
     pub fn pack_session_reply_packet(
@@ -395,383 +582,119 @@ impl Soeprotocol {
         })
     }
 
-    pub fn pack_net_status_request(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<NetStatusRequestPacket, serde_json::Error> =
-            self.get_net_status_request_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_net_status_request_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_net_status_request(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: NetStatusRequestPacket = self.get_net_status_request_object(packet)?;
+        Ok(self.pack_net_status_request_object(packet_object))
     }
 
-    pub fn pack_net_status_request_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: NetStatusRequestPacket = js_object.into_serde().unwrap();
-        self.pack_net_status_request_object(packet)
+    pub fn pack_net_status_request_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: NetStatusRequestPacket = js_object.into_serde()?;
+        Ok(self.pack_net_status_request_object(packet))
     }
 
-    pub fn pack_net_status_reply(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: NetStatusReplyPacket = self.get_net_status_reply_object(packet);
-        self.pack_net_status_reply_object(packet_object)
+    pub fn pack_net_status_reply(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: NetStatusReplyPacket = self.get_net_status_reply_object(packet)?;
+        Ok(self.pack_net_status_reply_object(packet_object))
     }
 
-    pub fn pack_net_status_reply_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: NetStatusReplyPacket = js_object.into_serde().unwrap();
-        self.pack_net_status_reply_object(packet)
+    pub fn pack_net_status_reply_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: NetStatusReplyPacket = js_object.into_serde()?;
+        Ok(self.pack_net_status_reply_object(packet))
     }
 
-    pub fn pack_multi(&mut self, packet: String) -> Vec<u8> {
-        let multi_packets: Result<SubBasePackets, serde_json::Error> =
-            self.get_multi_object(packet);
-        if let Ok(multi_packets) = multi_packets {
-            self.pack_multi_object(multi_packets)
-        } else {
-            gen_deserializing_error_json(multi_packets.err().unwrap())
-        }
+    pub fn pack_multi(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let multi_packets: SubBasePackets = self.get_multi_object(packet)?;
+        Ok(self.pack_multi_object(multi_packets))
     }
 
-    pub fn pack_multi_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: SubBasePackets = js_object.into_serde().unwrap();
-        self.pack_multi_object(packet)
+    pub fn pack_multi_fromjs(&mut self, js_object: &JsValue) -> Result<Vec<u8>, ProtocolError> {
+        let packet: SubBasePackets = js_object.into_serde()?;
+        Ok(self.pack_multi_object(packet))
     }
 
-    pub fn pack_group(&mut self, packet: String) -> Vec<u8> {
-        let group_packets: Result<SubBasePackets, serde_json::Error> =
-            self.get_multi_object(packet);
-        if let Ok(group_packets) = group_packets {
-            self.pack_group_object(group_packets)
-        } else {
-            gen_deserializing_error_json(group_packets.err().unwrap())
-        }
+    pub fn pack_group(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let group_packets: SubBasePackets = self.get_multi_object(packet)?;
+        Ok(self.pack_group_object(group_packets))
     }
 
-    pub fn pack_group_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: SubBasePackets = js_object.into_serde().unwrap();
-        self.pack_group_object(packet)
+    pub fn pack_group_fromjs(&mut self, js_object: &JsValue) -> Result<Vec<u8>, ProtocolError> {
+        let packet: SubBasePackets = js_object.into_serde()?;
+        Ok(self.pack_group_object(packet))
     }
 
-    pub fn pack_data(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<DataPacket, serde_json::Error> = self.get_data_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_data_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_data(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: DataPacket = self.get_data_object(packet)?;
+        Ok(self.pack_data_object(packet_object))
     }
 
-    pub fn pack_data_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: DataPacket = js_object.into_serde().unwrap();
-        self.pack_data_object(packet)
+    pub fn pack_data_fromjs(&mut self, js_object: &JsValue) -> Result<Vec<u8>, ProtocolError> {
+        let packet: DataPacket = js_object.into_serde()?;
+        Ok(self.pack_data_object(packet))
     }
 
     pub fn pack_data_packet(&mut self, data: Vec<u8>, sequence: u16) -> Vec<u8> {
         self.pack_data_object(DataPacket::new(data, sequence))
     }
 
-    pub fn pack_fragment_data(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<DataPacket, serde_json::Error> = self.get_data_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_fragment_data_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_fragment_data(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: DataPacket = self.get_data_object(packet)?;
+        Ok(self.pack_fragment_data_object(packet_object))
     }
 
-    pub fn pack_fragment_data_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: DataPacket = js_object.into_serde().unwrap();
-        self.pack_fragment_data_object(packet)
+    pub fn pack_fragment_data_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: DataPacket = js_object.into_serde()?;
+        Ok(self.pack_fragment_data_object(packet))
     }
 
     pub fn pack_fragment_data_packet(&mut self, data: Vec<u8>, sequence: u16) -> Vec<u8> {
         self.pack_fragment_data_object(DataPacket::new(data, sequence))
     }
 
-    pub fn pack_out_of_order(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<AckPacket, serde_json::Error> = self.get_ack_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_out_of_order_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_out_of_order(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: AckPacket = self.get_ack_object(packet)?;
+        Ok(self.pack_out_of_order_object(packet_object))
     }
 
-    pub fn pack_out_of_order_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: AckPacket = js_object.into_serde().unwrap();
-        self.pack_out_of_order_object(packet)
+    pub fn pack_out_of_order_fromjs(
+        &mut self,
+        js_object: &JsValue,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let packet: AckPacket = js_object.into_serde()?;
+        Ok(self.pack_out_of_order_object(packet))
     }
 
     pub fn pack_out_of_order_packet(&mut self, sequence: u16) -> Vec<u8> {
-        self.pack_out_of_order_object(AckPacket { sequence })
+        self.pack_out_of_order_object(AckPacket::new(sequence))
     }
 
-    pub fn pack_ack(&mut self, packet: String) -> Vec<u8> {
-        let packet_object: Result<AckPacket, serde_json::Error> = self.get_ack_object(packet);
-        if let Ok(packet_object) = packet_object {
-            self.pack_ack_object(packet_object)
-        } else {
-            gen_deserializing_error_json(packet_object.err().unwrap())
-        }
+    pub fn pack_ack(&mut self, packet: String) -> Result<Vec<u8>, ProtocolError> {
+        let packet_object: AckPacket = self.get_ack_object(packet)?;
+        Ok(self.pack_ack_object(packet_object))
     }
 
-    pub fn pack_ack_fromjs(&mut self, js_object: &JsValue) -> Vec<u8> {
-        let packet: AckPacket = js_object.into_serde().unwrap();
-        self.pack_ack_object(packet)
+    pub fn pack_ack_fromjs(&mut self, js_object: &JsValue) -> Result<Vec<u8>, ProtocolError> {
+        let packet: AckPacket = js_object.into_serde()?;
+        Ok(self.pack_ack_object(packet))
     }
 
     pub fn pack_ack_packet(&mut self, sequence: u16) -> Vec<u8> {
-        self.pack_ack_object(AckPacket { sequence })
+        self.pack_ack_object(AckPacket::new(sequence))
     }
 
     pub fn parse(&mut self, data: Vec<u8>) -> String {
-        let mut rdr = Cursor::new(&data);
-        let opcode: SoeOpcode = if data.len() >= 2 {
-            self.get_opcode(&mut rdr)
-        } else {
-            SoeOpcode::Unknown
-        };
-
-        match opcode {
-            SoeOpcode::SessionRequest => self.parse_session_request(rdr),
-            SoeOpcode::SessionReply => self.parse_session_reply(rdr),
-            SoeOpcode::MultiPacket => self.parse_multi(rdr),
-            SoeOpcode::Group => self.parse_multi(rdr),
-            SoeOpcode::Disconnect => self.parse_disconnect(rdr),
-            SoeOpcode::Ping => r#"{"name":"Ping"}"#.to_string(),
-            SoeOpcode::NetStatusRequest => self.parse_net_status_request(rdr),
-            SoeOpcode::NetStatusReply => self.parse_net_status_reply(rdr),
-            SoeOpcode::Data => self.parse_data(rdr, opcode as u16),
-            SoeOpcode::DataFragment => self.parse_data(rdr, opcode as u16),
-            SoeOpcode::OutOfOrder => self.parse_ack(rdr, opcode as u16),
-            SoeOpcode::Ack => self.parse_ack(rdr, opcode as u16),
-            SoeOpcode::Ordered => self.parse_ordered(rdr),
-            SoeOpcode::FatalError => format!(r#"{{"name":"FatalError","raw":{:?}}}"#, data),
-            SoeOpcode::Unknown => format!(r#"{{"name":"Unknown","raw":{:?}}}"#, data),
-        }
-    }
-
-    fn parse_ordered(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        if !check_min_size(&rdr, PacketsMinSize::DataPacket as usize, self.use_crc) {
-            return gen_size_error_json(rdr);
-        }
-        let order = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        let data_end: u64 = get_data_end(&rdr, self.use_crc);
-        let mut crc: u16 = 0;
-        if self.use_crc {
-            rdr.set_position(data_end);
-            crc = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        }
-        let vec = rdr.get_ref().to_vec();
-        let data = &vec[4..data_end as usize];
-        // check that crc value is correct
-        if self.use_crc {
-            let packet_without_crc = &vec[0..data_end as usize];
-            let crc_value =
-                (crc32(&&mut packet_without_crc.to_vec(), self.crc_seed as usize) & 0xffff) as u16;
-            if crc_value != crc {
-                return gen_crc_error_json(&vec, crc_value, crc);
-            }
-        }
-        format!(
-            r#"{{"name":"Ordered","order":{},"data":{:?}}}"#,
-            order, data
-        )
-    }
-    fn parse_session_request(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        if !check_min_size(&rdr, PacketsMinSize::SessionRequest as usize, false) {
-            return gen_size_error_json(rdr);
-        }
-
-        let crc_length = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let session_id = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let udp_length = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let protocol_data_position = rdr.position() as usize;
-        let raw_data = rdr.into_inner();
-        let protocol = str_from_u8_nul_utf8_checked(&raw_data[protocol_data_position..]);
-        format!(
-            r#"{{"name":"SessionRequest","protocol_version":{},"session_id":{},"udp_length":{},"protocol":"{}"}}"#,
-            crc_length, session_id, udp_length, protocol
-        )
-    }
-
-    fn parse_session_reply(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        if rdr.get_ref().len() != PacketsMinSize::SessionReply as usize {
-            return gen_size_error_json(rdr);
-        }
-        let session_id = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let crc_seed = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let crc_length = rdr.read_u8().unwrap_or_default();
-        let encrypt_method = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        let udp_length = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        format!(
-            r#"{{"name":"SessionReply","session_id":{},"crc_seed":{},"crc_length":{},"encrypt_method":{},"udp_length":{}}}"#,
-            session_id, crc_seed, crc_length, encrypt_method, udp_length
-        )
-    }
-
-    fn parse_disconnect(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        if rdr.get_ref().len() < PacketsMinSize::Disconnect as usize {
-            return r#"{"name":"Disconnect" ,"session_id":null,"reason":"unknown"}"#.to_string();
-        }
-        let session_id = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let reason = disconnect_reason_to_string(rdr.read_u16::<BigEndian>().unwrap_or_default());
-        format!(
-            r#"{{"name":"Disconnect" ,"session_id":{},"reason":"{}"}}"#,
-            session_id, reason
-        )
-    }
-
-    fn parse_net_status_request(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        if rdr.get_ref().len() != PacketsMinSize::NetStatusPacket as usize {
-            return gen_size_error_json(rdr);
-        }
-        let client_tick_count = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        let last_client_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let average_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let shortest_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let longest_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let last_server_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let packets_sent = rdr.read_u64::<BigEndian>().unwrap_or_default();
-        let packets_received = rdr.read_u64::<BigEndian>().unwrap_or_default();
-        let unknown_field = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        format!(
-            r#"{{"name":"NetStatusRequest","client_tick_count":{},"last_client_update":{},"average_update":{},"shortest_update":{},"longest_update":{},"last_server_update":{},"packets_sent":{},"packets_received":{},"unknown_field":{}}}"#,
-            client_tick_count,
-            last_client_update,
-            average_update,
-            shortest_update,
-            longest_update,
-            last_server_update,
-            packets_sent,
-            packets_received,
-            unknown_field
-        )
-    }
-
-    fn parse_net_status_reply(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        if rdr.get_ref().len() != PacketsMinSize::NetStatusPacket as usize {
-            return gen_size_error_json(rdr);
-        }
-        let client_tick_count = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        let server_tick_count = rdr.read_u32::<BigEndian>().unwrap_or_default();
-        let client_packet_sent = rdr.read_u64::<BigEndian>().unwrap_or_default();
-        let client_packet_received = rdr.read_u64::<BigEndian>().unwrap_or_default();
-        let server_packet_sent = rdr.read_u64::<BigEndian>().unwrap_or_default();
-        let server_packet_received = rdr.read_u64::<BigEndian>().unwrap_or_default();
-        let unknown_field = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        format!(
-            r#"{{"name":"NetStatusReply","client_tick_count":{},"server_tick_count":{},"client_packet_sent":{},"client_packet_received":{},"server_packet_sent":{},"server_packet_received":{},"unknown_field":{}}}"#,
-            client_tick_count,
-            server_tick_count,
-            client_packet_sent,
-            client_packet_received,
-            server_packet_sent,
-            server_packet_received,
-            unknown_field
-        )
-    }
-
-    fn parse_multi(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>) -> String {
-        // check size
-        if !check_min_size(
-            &rdr,
-            PacketsMinSize::MultiPacket as usize,
-            self.is_using_crc(),
-        ) {
-            return gen_size_error_json(rdr);
-        }
-        let mut multi_result: String = r#"{"name": "MultiPacket","sub_packets":[ "#.to_owned();
-        let data_end: u64 = get_data_end(&rdr, self.is_using_crc());
-        let was_crc_enabled = self.is_using_crc();
-        if was_crc_enabled {
-            self.disable_crc();
-            rdr.set_position(data_end);
-            let crc: u16 = rdr.read_u16::<BigEndian>().unwrap_or_default();
-            let vec = rdr.clone().into_inner();
-            let packet_without_crc = &vec[0..data_end as usize];
-            let crc_value =
-                (crc32(&&mut packet_without_crc.to_vec(), self.crc_seed as usize) & 0xffff) as u16;
-            if crc_value != crc {
-                return gen_crc_error_json(vec, crc_value, crc);
-            }
-            rdr.set_position(2); // reset pos after the opcode
-        }
-        loop {
-            let sub_packet_data_length = read_data_length(&mut rdr);
-            if sub_packet_data_length == 0
-                || sub_packet_data_length as u64 + rdr.position() > data_end
-            {
-                return gen_corruption_error_json(rdr, sub_packet_data_length, data_end);
-            }
-            let sub_packet_data =
-                extract_subpacket_data(&rdr, rdr.position(), sub_packet_data_length);
-            rdr.set_position(sub_packet_data_length as u64 + rdr.position());
-            let sub_packet = self.parse(sub_packet_data);
-            multi_result.push_str(&sub_packet);
-            if rdr.position() == data_end {
-                break;
-            } else {
-                multi_result.push(',');
-            }
-        }
-        multi_result.push_str("]}");
-        if was_crc_enabled {
-            self.enable_crc();
-        }
-        multi_result
-    }
-
-    fn parse_data(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>, opcode: u16) -> String {
-        if !check_min_size(&rdr, PacketsMinSize::DataPacket as usize, self.use_crc) {
-            return gen_size_error_json(rdr);
-        }
-        let name = if opcode == 0x09 {
-            "Data"
-        } else {
-            "DataFragment"
-        };
-        let sequence = rdr.read_u16::<BigEndian>().unwrap_or_default();
-
-        let data_end: u64 = get_data_end(&rdr, self.use_crc);
-        let mut crc: u16 = 0;
-        if self.use_crc {
-            rdr.set_position(data_end);
-            crc = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        }
-        let vec = rdr.get_ref().to_vec();
-        let data = &vec[4..data_end as usize];
-        // check that crc value is correct
-        if self.use_crc {
-            let packet_without_crc = &vec[0..data_end as usize];
-            let crc_value =
-                (crc32(&&mut packet_without_crc.to_vec(), self.crc_seed as usize) & 0xffff) as u16;
-            if crc_value != crc {
-                return gen_crc_error_json(&vec, crc_value, crc);
-            }
+        match self.parse_packet(&data) {
+            Ok(packet) => packet.to_json(),
+            Err(error) => error.to_json(&data),
         }
-        format!(
-            r#"{{"name":"{}","sequence":{},"data":{:?}}}"#,
-            name, sequence, data
-        )
-    }
-
-    fn parse_ack(&mut self, mut rdr: Cursor<&std::vec::Vec<u8>>, opcode: u16) -> String {
-        if !check_min_size(&rdr, PacketsMinSize::Ack as usize, self.use_crc) {
-            return gen_size_error_json(rdr);
-        }
-        let name = if opcode == 0x15 { "Ack" } else { "OutOfOrder" };
-        let sequence = rdr.read_u16::<BigEndian>().unwrap_or_default();
-        if self.use_crc {
-            let crc = rdr.read_u16::<BigEndian>().unwrap_or_default();
-            let data_end: u64 = get_data_end(&rdr, self.use_crc);
-            let vec = rdr.into_inner();
-            let packet_without_crc = &vec[0..data_end as usize];
-            let crc_value =
-                (crc32(&&mut packet_without_crc.to_vec(), self.crc_seed as usize) & 0xffff) as u16;
-            if crc_value != crc {
-                return gen_crc_error_json(vec, crc_value, crc);
-            }
-        }
-        format!(r#"{{"name":"{}","sequence":{}}}"#, name, sequence)
     }
 
     pub fn get_crc_seed(&self) -> u32 {
@@ -780,15 +703,76 @@ impl Soeprotocol {
     pub fn set_crc_seed(&mut self, crc_seed: u32) {
         self.crc_seed = crc_seed;
     }
-    pub fn is_using_crc(&mut self) -> bool {
+    pub fn is_using_crc(&self) -> bool {
         self.use_crc
     }
     pub fn disable_crc(&mut self) {
         self.use_crc = false;
     }
+    pub fn get_crc_length(&self) -> u8 {
+        self.crc_length
+    }
+    pub fn set_crc_length(&mut self, crc_length: u8) -> Result<(), ProtocolError> {
+        if crc_length > MAX_CRC_LENGTH {
+            return Err(ProtocolError::Handshake(format!(
+                "unsupported crc length {}",
+                crc_length
+            )));
+        }
+        self.crc_length = crc_length;
+        Ok(())
+    }
     pub fn enable_crc(&mut self) {
         self.use_crc = true;
     }
+    pub fn is_using_compression(&self) -> bool {
+        self.compression_threshold.is_some()
+    }
+    pub fn enable_compression(&mut self, threshold: u32) {
+        self.compression_threshold = Some(threshold as usize);
+    }
+    pub fn disable_compression(&mut self) {
+        self.compression_threshold = None;
+    }
+    pub fn get_encrypt_method(&self) -> u16 {
+        self.encrypt_method as u16
+    }
+    pub fn set_encrypt_method(&mut self, encrypt_method: u16) -> Result<(), ProtocolError> {
+        self.encrypt_method = match encrypt_method {
+            0x0 => EncryptMethod::EncryptMethodNone,
+            0x3 => EncryptMethod::EncryptMethodXorBuffer,
+            0x4 => EncryptMethod::EncryptMethodXor,
+            _ => {
+                return Err(ProtocolError::Handshake(format!(
+                    "unsupported encrypt method {}",
+                    encrypt_method
+                )))
+            }
+        };
+        Ok(())
+    }
+    pub fn finalize(&self, packet: Vec<u8>) -> Vec<u8> {
+        self.finalize_packet(&packet)
+    }
+}
+
+fn is_session_packet(packet: &[u8]) -> bool {
+    packet.len() >= 2 && packet[0] == 0 && matches!(packet[1], 0x01 | 0x02)
+}
+
+// like session packets, pings and net status packets are parsed without crc nor encryption
+fn is_plain_packet(packet: &[u8]) -> bool {
+    is_session_packet(packet)
+        || (packet.len() >= 2 && packet[0] == 0 && matches!(packet[1], 0x06..=0x08))
+}
+
+// only multi and data packets carry the compression flag
+fn is_compressible(packet: &[u8]) -> bool {
+    packet.len() >= 2
+        && matches!(
+            u16::from_be_bytes([packet[0], packet[1]]),
+            0x03 | 0x09..=0x10 | 0x19 | 0x1B
+        )
 }
 
 #[cfg(test)]
@@ -796,7 +780,6 @@ mod tests {
 
     use super::super::crc::append_crc;
 
-    use super::super::protocol_errors::*;
     use super::*;
 
     #[test]
@@ -830,7 +813,9 @@ mod tests {
         let data_to_pack =
             r#"{"protocol_version":3,"session_id":1008176227,"udp_length":512,"protocol":"LoginUdp_9"}"#
                 .to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::SessionRequest, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::SessionRequest, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [
@@ -845,9 +830,8 @@ mod tests {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack =
             r#"{"protocol_version":3,"udp_length":512,"protocol":"LoginUdp_9"}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::SessionRequest, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::SessionRequest, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -878,7 +862,9 @@ mod tests {
     fn session_reply_pack_test() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(true, 0);
         let data_to_pack =  r#"{"session_id":1008176227,"crc_seed":0,"crc_length":2,"encrypt_method":256,"udp_length":512}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::SessionReply, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::SessionReply, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [0, 2, 60, 23, 140, 99, 0, 0, 0, 0, 2, 1, 0, 0, 0, 2, 0, 0, 0, 0, 3]
@@ -890,9 +876,8 @@ mod tests {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack =
             r#"{"crc_seed":0,"crc_length":2,"encrypt_method":256,"udp_length":512}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::SessionReply, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::SessionReply, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -929,7 +914,9 @@ mod tests {
         let data_to_pack =
         r#"{"average_update": 0, "client_tick_count": 64348, "last_client_update": 0, "last_server_update": 0, "longest_update": 0, "name": "NetStatusRequest", "packets_received": 1, "packets_sent": 2, "shortest_update": 0, "unknown_field": 60376}"#
                 .to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::NetStatusRequest, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::NetStatusRequest, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [
@@ -944,9 +931,8 @@ mod tests {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack =
         r#"{"client_packet_received": 0, "client_packet_sent": 0, "client_tick_count": 64348, "name": "NetStatusRequest", "server_packet_received": 1, "server_packet_sent": 2, "server_tick_count": 0}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::NetStatusRequest, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::NetStatusRequest, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -983,7 +969,9 @@ mod tests {
         let data_to_pack =
         r#"{"client_packet_received": 1, "client_packet_sent": 2, "client_tick_count": 64348, "name": "NetStatusReply", "server_packet_received": 2, "server_packet_sent": 1, "server_tick_count": 556254524, "unknown_field": 33748}"#
                 .to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::NetStatusReply, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::NetStatusReply, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [
@@ -998,9 +986,8 @@ mod tests {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack =
         r#"{"client_packet_received": 0, "client_packet_sent": 0, "client_tick_cozunt": 64348, "name": "NetStatusRequest", "server_packet_received": 1, "server_packet_sent": 2, "server_tick_count": 0}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::NetStatusRequest, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::NetStatusRequest, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -1015,7 +1002,9 @@ mod tests {
     fn ping_pack_test() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(true, 0);
         let data_to_pack: String = r#"{"name":"Ping"}"#.to_owned();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::Ping, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::Ping, data_to_pack)
+            .unwrap();
         assert_eq!(data_pack, [0, 6])
     }
 
@@ -1060,7 +1049,9 @@ mod tests {
     fn outoforder_pack_test() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack: String = r#"{"name":"OutOfOrder","sequence":1}"#.to_owned();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::OutOfOrder, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::OutOfOrder, data_to_pack)
+            .unwrap();
         assert_eq!(data_pack, [0, 17, 0, 1])
     }
 
@@ -1068,9 +1059,8 @@ mod tests {
     fn outoforder_pack_test_deserializing_error() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack: String = r#"{"sequednce":1}"#.to_owned();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::OutOfOrder, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::OutOfOrder, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -1103,7 +1093,9 @@ mod tests {
     fn ack_pack_test() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack: String = r#"{"name":"Ack","sequence":1}"#.to_owned();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::Ack, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::Ack, data_to_pack)
+            .unwrap();
         assert_eq!(data_pack, [0, 21, 0, 1])
     }
 
@@ -1111,9 +1103,8 @@ mod tests {
     fn ack_pack_test_deserializing_error() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack: String = r#"{"name":"Ack"}"#.to_owned();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::Ack, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::Ack, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -1200,7 +1191,9 @@ mod tests {
         71, 228, 114, 81, 54, 5, 184, 205, 104, 0, 125, 184, 210, 74, 0, 247, 152, 225,
         169, 102, 204, 158, 233, 202, 228, 34, 202, 238, 136, 31, 3, 121, 222, 106, 11,
         247, 177, 138, 145, 21, 221, 187, 36, 170, 37, 171, 6, 32, 11, 180, 97, 10, 246]]}"#.to_owned();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::MultiPacket, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::MultiPacket, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [
@@ -1219,9 +1212,8 @@ mod tests {
         71, 228, 114, 81, 54, 5, 184, 205, 104, 0, 125, 184, 210, 74, 0, 247, 152, 225,
         169, 102, 204, 158, 233, 202, 228, 34, 202, 238, 136, 31, 3, 121, 222, 106, 11,
         247, 177, 138, 145, 21, 221, 187, 36, 170, 37, 171, 6, 32, 11, 180, 97, 10, 246]]}"#.to_owned();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::MultiPacket, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::MultiPacket, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -1314,7 +1306,9 @@ mod tests {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack =
             r#"{"sequence":0,"data":[2,1,1,0,0,0,1,1,3,0,0,0,115,111,101,0,0,0,0]}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::Data, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::Data, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [0, 9, 0, 0, 2, 1, 1, 0, 0, 0, 1, 1, 3, 0, 0, 0, 115, 111, 101, 0, 0, 0, 0]
@@ -1326,7 +1320,9 @@ mod tests {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack =
             r#"{"sequence":1,"data":[2,1,1,0,0,0,1,1,3,0,0,0,115,111,101,0,0,0,0]}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::Ordered, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::Ordered, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [0, 27, 0, 1, 2, 1, 1, 0, 0, 0, 1, 1, 3, 0, 0, 0, 115, 111, 101, 0, 0, 0, 0]
@@ -1337,9 +1333,8 @@ mod tests {
     fn data_pack_test_deserializing_error() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack = r#"{"data":[2,1,1,0,0,0,1,1,3,0,0,0,115,111,101,0,0,0,0]}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::Data, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::Data, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
     }
 
     #[test]
@@ -1399,7 +1394,9 @@ mod tests {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack =
             r#"{"sequence":2,"data":[2,1,1,0,0,0,1,1,3,0,0,0,115,111,101,0,0,0,0]}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::DataFragment, data_to_pack);
+        let data_pack: Vec<u8> = soeprotocol_class
+            .pack(SoeOpcode::DataFragment, data_to_pack)
+            .unwrap();
         assert_eq!(
             data_pack,
             [0, 13, 0, 2, 2, 1, 1, 0, 0, 0, 1, 1, 3, 0, 0, 0, 115, 111, 101, 0, 0, 0, 0]
@@ -1410,8 +1407,502 @@ mod tests {
     fn data_fragment_pack_test_deserializing_error() {
         let mut soeprotocol_class = super::Soeprotocol::initialize(false, 0);
         let data_to_pack = r#"{"data":[2,1,1,0,0,0,1,1,3,0,0,0,115,111,101,0,0,0,0]}"#.to_string();
-        let data_pack: Vec<u8> = soeprotocol_class.pack(SoeOpcode::DataFragment, data_to_pack);
-        let data_pack_opcode: u16 = u16::from_be_bytes([data_pack[0], data_pack[1]]);
-        assert_eq!(data_pack_opcode, ErrorType::Deserializing as u16)
+        let data_pack = soeprotocol_class.pack(SoeOpcode::DataFragment, data_to_pack);
+        assert!(matches!(data_pack, Err(ProtocolError::Deserialize(_))))
+    }
+
+    #[test]
+    fn parse_packet_data_test() {
+        let soeprotocol_class = super::Soeprotocol::initialize(true, 0);
+        let mut data_to_parse: Vec<u8> = [0, 9, 0, 4, 1, 2].to_vec();
+        append_crc(&mut data_to_parse, 0);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data_to_parse),
+            Ok(SoePacket::Data(DataPacket::new(vec![1, 2], 4)))
+        )
+    }
+
+    #[test]
+    fn parse_packet_multi_test() {
+        let soeprotocol_class = super::Soeprotocol::initialize(false, 0);
+        let data_to_parse: [u8; 13] = [0, 3, 4, 0, 21, 0, 206, 5, 0, 9, 0, 1, 7];
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data_to_parse),
+            Ok(SoePacket::Multi(vec![
+                SoePacket::Ack(AckPacket::new(206)),
+                SoePacket::Data(DataPacket::new(vec![7], 1)),
+            ]))
+        )
+    }
+
+    #[test]
+    fn parse_packet_crc_error_test() {
+        let soeprotocol_class = super::Soeprotocol::initialize(true, 0);
+        let data_to_parse: [u8; 6] = [0, 17, 0, 1, 142, 100];
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data_to_parse),
+            Err(ProtocolError::Crc {
+                expected: 9912,
+                given: 36452
+            })
+        )
+    }
+
+    #[test]
+    fn parse_packet_size_error_test() {
+        let soeprotocol_class = super::Soeprotocol::initialize(true, 0);
+        let data_to_parse: [u8; 3] = [0, 21, 111];
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data_to_parse),
+            Err(ProtocolError::Size { size: 3 })
+        )
+    }
+
+    #[test]
+    fn pack_reliable_data_single_test() {
+        let mut soeprotocol_class = super::Soeprotocol::initialize(true, 0);
+        let packets = soeprotocol_class
+            .pack_reliable_data(&[1, 2, 3], 0, 7, 512, 2)
+            .unwrap();
+        assert_eq!(packets, vec![vec![0, 9, 0, 7, 1, 2, 3]])
+    }
+
+    #[test]
+    fn pack_reliable_data_fragments_test() {
+        let mut soeprotocol_class = super::Soeprotocol::initialize(true, 0);
+        let data: Vec<u8> = (0..20).collect();
+        let packets = soeprotocol_class
+            .pack_reliable_data(&data, 0, 65535, 16, 2)
+            .unwrap();
+        assert_eq!(
+            packets,
+            vec![
+                vec![0, 13, 255, 255, 0, 0, 0, 20, 0, 1, 2, 3, 4, 5],
+                vec![0, 13, 0, 0, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+                vec![0, 13, 0, 1, 16, 17, 18, 19],
+            ]
+        );
+        assert!(packets.iter().all(|packet| packet.len() + 2 <= 16))
+    }
+
+    #[test]
+    fn pack_reliable_data_compression_test() {
+        let mut soeprotocol_class = super::Soeprotocol::initialize(true, 0);
+        soeprotocol_class.enable_compression(0);
+        let data: Vec<u8> = (0..200).map(|i| (i * 37 % 251) as u8).collect();
+        let packets = soeprotocol_class
+            .pack_reliable_data(&data, 0, 0, 32, 2)
+            .unwrap();
+        assert!(packets.len() > 1);
+        assert!(packets
+            .iter()
+            .all(|packet| soeprotocol_class.finalize_packet(packet).len() <= 32))
+    }
+
+    #[test]
+    fn pack_reliable_data_udp_length_too_small_test() {
+        let mut soeprotocol_class = super::Soeprotocol::initialize(true, 0);
+        assert_eq!(
+            soeprotocol_class.pack_reliable_data(&[1, 2, 3, 4, 5, 6], 0, 0, 10, 2),
+            Err(ProtocolError::Size { size: 10 })
+        )
+    }
+
+    #[test]
+    fn parse_packet_channel_test() {
+        let soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut data_packet = DataPacket::new(vec![1, 2, 3], 5);
+        data_packet.set_channel(3).unwrap();
+        assert_eq!(
+            soeprotocol_class.parse_packet(&[0, 12, 0, 5, 1, 2, 3]),
+            Ok(SoePacket::Data(data_packet))
+        );
+        let mut ack_packet = AckPacket::new(5);
+        ack_packet.set_channel(1).unwrap();
+        assert_eq!(
+            soeprotocol_class.parse_packet(&[0, 22, 0, 5]),
+            Ok(SoePacket::Ack(ack_packet))
+        );
+        assert_eq!(
+            soeprotocol_class
+                .parse_packet(&[0, 16, 0, 5, 1])
+                .unwrap()
+                .to_json(),
+            r#"{"name":"DataFragment","channel":3,"sequence":5,"data":[1]}"#
+        )
+    }
+
+    #[test]
+    fn pack_channel_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        assert_eq!(
+            soeprotocol_class
+                .pack_data(r#"{"sequence":1,"channel":1,"data":[7]}"#.to_owned())
+                .unwrap(),
+            vec![0, 10, 0, 1, 7]
+        );
+        let mut ack_packet = AckPacket::new(1);
+        ack_packet.set_channel(2).unwrap();
+        assert_eq!(
+            soeprotocol_class.pack_out_of_order_object(ack_packet),
+            vec![0, 19, 0, 1]
+        );
+        assert_eq!(SoeOpcode::get_channel(0x18), 3);
+        assert_eq!(SoeOpcode::get_channel(0x1B), 0)
+    }
+
+    #[test]
+    fn pack_invalid_channel_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        assert!(soeprotocol_class
+            .pack_data(r#"{"sequence":1,"channel":7,"data":[7]}"#.to_owned())
+            .is_err());
+        let mut ack_packet = AckPacket::new(1);
+        assert_eq!(
+            ack_packet.set_channel(4),
+            Err(ProtocolError::Channel { channel: 4 })
+        );
+        assert_eq!(ack_packet.get_channel(), 0);
+        let mut data_packet = DataPacket::new(vec![1], 1);
+        assert_eq!(
+            data_packet.set_channel(4),
+            Err(ProtocolError::Channel { channel: 4 })
+        );
+        assert_eq!(
+            soeprotocol_class.pack_reliable_data(&[1], 4, 0, 512, 2),
+            Err(ProtocolError::Channel { channel: 4 })
+        );
+    }
+
+    #[test]
+    fn crc_length_round_trip_test() {
+        for crc_length in 0..=4 {
+            let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+            soeprotocol_class.set_crc_length(crc_length).unwrap();
+            let mut data = soeprotocol_class.pack_data_packet(vec![1, 2, 3], 4);
+            soeprotocol_class.append_crc(&mut data);
+            assert_eq!(data.len(), 7 + crc_length as usize);
+            assert_eq!(
+                soeprotocol_class.parse_packet(&data),
+                Ok(SoePacket::Data(DataPacket::new(vec![1, 2, 3], 4)))
+            );
+        }
+    }
+
+    #[test]
+    fn crc_length_mismatch_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        soeprotocol_class.set_crc_length(3).unwrap();
+        let mut data = soeprotocol_class.pack_ack_packet(4);
+        soeprotocol_class.append_crc(&mut data);
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(matches!(
+            soeprotocol_class.parse_packet(&data),
+            Err(ProtocolError::Crc { .. })
+        ));
+        assert!(soeprotocol_class.set_crc_length(5).is_err());
+        assert_eq!(soeprotocol_class.get_crc_length(), 3)
+    }
+
+    #[test]
+    fn compression_data_round_trip_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        soeprotocol_class.enable_compression(50);
+        let data = vec![3; 300];
+        let packet = soeprotocol_class.pack_data_packet(data.clone(), 1);
+        let finalized = soeprotocol_class.finalize_packet(&packet);
+        assert_eq!(finalized[2], COMPRESSION_FLAG_ZLIB);
+        assert!(finalized.len() < packet.len());
+        assert_eq!(
+            soeprotocol_class.parse_packet(&finalized),
+            Ok(SoePacket::Data(DataPacket::new(data, 1)))
+        );
+        let small_packet = soeprotocol_class.pack_data_packet(vec![1, 2], 2);
+        let finalized = soeprotocol_class.finalize_packet(&small_packet);
+        assert_eq!(finalized[..5], [0, 9, COMPRESSION_FLAG_NONE, 0, 2]);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&finalized),
+            Ok(SoePacket::Data(DataPacket::new(vec![1, 2], 2)))
+        )
+    }
+
+    #[test]
+    fn compression_multi_round_trip_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        soeprotocol_class.enable_compression(0);
+        let mut multi_packet = SubBasePackets::new();
+        multi_packet.add_sub_packet(soeprotocol_class.pack_ack_packet(4));
+        multi_packet.add_sub_packet(soeprotocol_class.pack_data_packet(vec![5; 100], 5));
+        let packet = soeprotocol_class.pack_multi_object(multi_packet);
+        let finalized = soeprotocol_class.finalize_packet(&packet);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&finalized),
+            Ok(SoePacket::Multi(vec![
+                SoePacket::Ack(AckPacket::new(4)),
+                SoePacket::Data(DataPacket::new(vec![5; 100], 5)),
+            ]))
+        );
+        // acks are never flagged
+        let ack = soeprotocol_class.pack_ack_packet(4);
+        let ack = soeprotocol_class.finalize_packet(&ack);
+        assert_eq!(ack.len(), 6);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&ack),
+            Ok(SoePacket::Ack(AckPacket::new(4)))
+        )
+    }
+
+    #[test]
+    fn compression_crc_error_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        soeprotocol_class.enable_compression(0);
+        let packet = soeprotocol_class.pack_data_packet(vec![3; 100], 1);
+        let mut finalized = soeprotocol_class.finalize_packet(&packet);
+        finalized[4] ^= 0xff;
+        assert!(matches!(
+            soeprotocol_class.parse_packet(&finalized),
+            Err(ProtocolError::Crc { .. })
+        ))
+    }
+
+    #[test]
+    fn encrypt_method_round_trip_test() {
+        for encrypt_method in [
+            EncryptMethod::EncryptMethodXor,
+            EncryptMethod::EncryptMethodXorBuffer,
+        ] {
+            let mut soeprotocol_class = Soeprotocol::initialize(true, 0x12345678);
+            soeprotocol_class
+                .set_encrypt_method(encrypt_method as u16)
+                .unwrap();
+            soeprotocol_class.enable_compression(20);
+            let packet = soeprotocol_class.pack_data_packet(vec![9; 64], 3);
+            let finalized = soeprotocol_class.finalize_packet(&packet);
+            assert_ne!(finalized[2], COMPRESSION_FLAG_ZLIB);
+            assert_eq!(
+                soeprotocol_class.parse_packet(&finalized),
+                Ok(SoePacket::Data(DataPacket::new(vec![9; 64], 3)))
+            );
+            let ack = soeprotocol_class.pack_ack_packet(3);
+            let finalized = soeprotocol_class.finalize_packet(&ack);
+            assert_ne!(finalized[2..4], ack[2..4]);
+            assert_eq!(
+                soeprotocol_class.parse_packet(&finalized),
+                Ok(SoePacket::Ack(AckPacket::new(3)))
+            );
+        }
+    }
+
+    #[test]
+    fn encrypt_method_unsupported_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 0);
+        assert!(soeprotocol_class
+            .set_encrypt_method(EncryptMethod::EncryptMethodUserSupplied as u16)
+            .is_err());
+        assert_eq!(
+            soeprotocol_class.get_encrypt_method(),
+            EncryptMethod::EncryptMethodNone as u16
+        )
+    }
+
+    #[test]
+    fn pack_disconnect_packet_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let data = soeprotocol_class
+            .pack_disconnect_packet(0x01020304, DisconnectReason::DisconnectReasonTimeout);
+        assert_eq!(data, [0, 5, 1, 2, 3, 4, 0, 1]);
+        assert_eq!(
+            soeprotocol_class.parse(data),
+            r#"{"name":"Disconnect","session_id":16909060,"reason":"DisconnectReasonTimeout","reason_code":1}"#
+        )
+    }
+
+    #[test]
+    fn pack_disconnect_packet_crc_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        let mut data = soeprotocol_class.pack_disconnect_packet(
+            0x01020304,
+            DisconnectReason::DisconnectReasonProtocolMismatch,
+        );
+        assert_eq!(data.len(), 10);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data),
+            Ok(SoePacket::Disconnect(Some(DisconnectPacket::new(
+                0x01020304,
+                DisconnectReason::DisconnectReasonProtocolMismatch as u16
+            ))))
+        );
+        data[9] ^= 0xff;
+        assert!(matches!(
+            soeprotocol_class.parse_packet(&data),
+            Err(ProtocolError::Crc { .. })
+        ))
+    }
+
+    #[test]
+    fn parse_disconnect_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&[0, 5]),
+            Ok(SoePacket::Disconnect(None))
+        );
+        assert_eq!(
+            soeprotocol_class.parse(vec![0, 5, 0, 0, 0, 1, 0, 42]),
+            r#"{"name":"Disconnect","session_id":1,"reason":"unknown","reason_code":42}"#
+        );
+        assert_eq!(
+            soeprotocol_class
+                .pack(
+                    SoeOpcode::Disconnect,
+                    r#"{"session_id":1,"reason":6}"#.to_owned()
+                )
+                .unwrap(),
+            [0, 5, 0, 0, 0, 1, 0, 6]
+        )
+    }
+
+    #[test]
+    fn group_round_trip_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut group_packet = SubBasePackets::new();
+        group_packet.add_sub_packet(soeprotocol_class.pack_ack_packet(1));
+        group_packet.add_sub_packet(soeprotocol_class.pack_data_packet(vec![4, 5], 2));
+        let data = soeprotocol_class.pack_group_object(group_packet);
+        assert_eq!(data[..2], [0, 0x19]);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data),
+            Ok(SoePacket::Group(vec![
+                SoePacket::Ack(AckPacket::new(1)),
+                SoePacket::Data(DataPacket::new(vec![4, 5], 2)),
+            ]))
+        );
+        assert_eq!(
+            soeprotocol_class.parse(data),
+            r#"{"name":"Group","sub_packets":[{"name":"Ack","sequence":1},{"name":"Data","sequence":2,"data":[4, 5]}]}"#
+        )
+    }
+
+    #[test]
+    fn group_crc_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        let mut group_packet = SubBasePackets::new();
+        group_packet.add_sub_packet(soeprotocol_class.pack_ack_packet(1));
+        group_packet.add_sub_packet(soeprotocol_class.pack_ack_packet(2));
+        let mut data = soeprotocol_class.pack_group_object(group_packet);
+        soeprotocol_class.append_crc(&mut data);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data),
+            Ok(SoePacket::Group(vec![
+                SoePacket::Ack(AckPacket::new(1)),
+                SoePacket::Ack(AckPacket::new(2)),
+            ]))
+        );
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(matches!(
+            soeprotocol_class.parse_packet(&data),
+            Err(ProtocolError::Crc { .. })
+        ));
+        // without crc the footer is read as a corrupted sub packet
+        soeprotocol_class.disable_crc();
+        assert!(matches!(
+            soeprotocol_class.parse_packet(&data),
+            Err(ProtocolError::Corruption { .. })
+        ))
+    }
+
+    #[test]
+    fn fatal_error_round_trip_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        let data = soeprotocol_class.pack_fatal_error_packet(3, "bad crc".to_owned());
+        assert_eq!(
+            data[..13],
+            [0, 29, 0, 3, 98, 97, 100, 32, 99, 114, 99, 0, data[12]]
+        );
+        assert_eq!(data.len(), 14);
+        assert_eq!(
+            soeprotocol_class.parse_packet(&data),
+            Ok(SoePacket::FatalError(FatalErrorPacket::new(
+                3,
+                "bad crc".to_owned()
+            )))
+        );
+        assert_eq!(
+            soeprotocol_class.parse(data),
+            r#"{"name":"FatalError","error_code":3,"message":"bad crc"}"#
+        )
+    }
+
+    #[test]
+    fn fatal_error_json_escape_test() {
+        let message = "a\"b\\c\u{1b}".to_owned();
+        let json = SoePacket::FatalError(FatalErrorPacket::new(3, message.clone())).to_json();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["name"], "FatalError");
+        assert_eq!(parsed["error_code"], 3);
+        assert_eq!(parsed["message"], message.as_str())
+    }
+
+    #[test]
+    fn fatal_error_pack_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        assert_eq!(
+            soeprotocol_class
+                .pack(
+                    SoeOpcode::FatalError,
+                    r#"{"error_code":1,"message":"a"}"#.to_owned()
+                )
+                .unwrap(),
+            [0, 29, 0, 1, 97, 0]
+        );
+        assert_eq!(
+            soeprotocol_class.parse_packet(&[0, 29, 0]),
+            Err(ProtocolError::Size { size: 3 })
+        )
+    }
+
+    #[test]
+    fn pack_into_slice_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let packet = SessionReplyPacket::new(1, 2, 2, 0, 512);
+        let mut buffer = [0; 32];
+        let length = packet.pack_into_slice(&mut buffer).unwrap();
+        assert_eq!(
+            buffer[..length],
+            soeprotocol_class.pack_session_reply_object(packet.clone())
+        );
+        assert_eq!(
+            packet.pack_into_slice(&mut buffer[..20]),
+            Err(ProtocolError::BufferTooSmall {
+                needed: 21,
+                capacity: 20
+            })
+        )
+    }
+
+    #[test]
+    fn pack_into_append_test() {
+        let mut wtr = vec![];
+        SoePacket::Ack(AckPacket::new(1)).pack_into(&mut wtr);
+        SoePacket::Multi(vec![
+            SoePacket::Ack(AckPacket::new(1)),
+            SoePacket::Data(DataPacket::new(vec![7], 2)),
+        ])
+        .pack_into(&mut wtr);
+        assert_eq!(wtr, [0, 21, 0, 1, 0, 3, 4, 0, 21, 0, 1, 5, 0, 9, 0, 2, 7])
+    }
+
+    #[test]
+    fn pack_packet_ref_test() {
+        let soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let data = [0, 25, 4, 0, 21, 0, 1, 5, 0, 9, 0, 2, 7];
+        let mut scratch = vec![];
+        let packet = soeprotocol_class
+            .parse_packet_ref(&data, &mut scratch)
+            .unwrap();
+        let mut buffer = [0; 16];
+        let length = packet.pack_into_slice(&mut buffer).unwrap();
+        assert_eq!(buffer[..length], data)
     }
 }
@@ -0,0 +1,196 @@
+use super::soeprotocol::{SoeOpcode, Soeprotocol};
+use super::soeprotocol_functions::write_data_length;
+
+// multi packet sub packets lengths are read back as a single byte
+const MAX_SUB_PACKET_SIZE: usize = 0xFF;
+const MULTI_HEADER_SIZE: usize = 2;
+
+// coalesce small outgoing packets into multi packets, frames are finalized by the session codec
+#[derive(Debug)]
+pub struct MultiPacketBatcher {
+    udp_length: usize,
+    flush_delay: u64,
+    sub_packets: Vec<Vec<u8>>,
+    frame_size: usize,
+    oldest: Option<u64>,
+}
+
+impl MultiPacketBatcher {
+    pub fn new(udp_length: u32, flush_delay: u64) -> Self {
+        Self {
+            udp_length: udp_length as usize,
+            flush_delay,
+            sub_packets: vec![],
+            frame_size: MULTI_HEADER_SIZE,
+            oldest: None,
+        }
+    }
+
+    // queue a packed packet, returns the frames that had to be sent to make room for it
+    pub fn push(&mut self, soeprotocol: &Soeprotocol, packet: Vec<u8>, now: u64) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        let max_frame_size = self.get_max_frame_size(soeprotocol);
+        let sub_packet_size = 1 + packet.len();
+        if packet.len() > MAX_SUB_PACKET_SIZE
+            || MULTI_HEADER_SIZE + sub_packet_size > max_frame_size
+        {
+            // too big to be batched, keep the ordering by flushing what is queued first
+            frames.extend(self.flush(soeprotocol));
+            frames.push(soeprotocol.finalize_packet(&packet));
+            return frames;
+        }
+        if self.frame_size + sub_packet_size > max_frame_size {
+            frames.extend(self.flush(soeprotocol));
+        }
+        self.oldest.get_or_insert(now);
+        self.frame_size += sub_packet_size;
+        self.sub_packets.push(packet);
+        frames
+    }
+
+    // flush once the oldest queued packet waited long enough
+    pub fn poll(&mut self, soeprotocol: &Soeprotocol, now: u64) -> Option<Vec<u8>> {
+        let oldest = self.oldest?;
+        if now.saturating_sub(oldest) >= self.flush_delay {
+            return self.flush(soeprotocol);
+        }
+        None
+    }
+
+    pub fn flush(&mut self, soeprotocol: &Soeprotocol) -> Option<Vec<u8>> {
+        self.oldest = None;
+        self.frame_size = MULTI_HEADER_SIZE;
+        let mut sub_packets = std::mem::take(&mut self.sub_packets);
+        let frame = match sub_packets.len() {
+            0 => return None,
+            // no need for a multi packet around a single packet
+            1 => sub_packets.remove(0),
+            _ => {
+                let mut frame = Vec::with_capacity(self.udp_length);
+                frame.extend_from_slice(&(SoeOpcode::MultiPacket as u16).to_be_bytes());
+                for sub_packet in &sub_packets {
+                    write_data_length(&mut frame, sub_packet.len());
+                    frame.extend_from_slice(sub_packet);
+                }
+                frame
+            }
+        };
+        Some(soeprotocol.finalize_packet(&frame))
+    }
+
+    pub fn get_queued_count(&self) -> usize {
+        self.sub_packets.len()
+    }
+
+    pub fn get_flush_deadline(&self) -> Option<u64> {
+        self.oldest
+            .map(|oldest| oldest.saturating_add(self.flush_delay))
+    }
+
+    // room left once the codec added its footer and compression flag
+    fn get_max_frame_size(&self, soeprotocol: &Soeprotocol) -> usize {
+        self.udp_length
+            .saturating_sub(soeprotocol.get_finalize_overhead())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::soeprotocol::Soeprotocol;
+    use super::super::soeprotocol_packets_structs::{AckPacket, SoePacket};
+
+    #[test]
+    fn batcher_multi_test() {
+        let soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut batcher = super::MultiPacketBatcher::new(512, 10);
+        assert!(batcher
+            .push(&soeprotocol_class, vec![0, 21, 0, 1], 0)
+            .is_empty());
+        assert!(batcher.push(&soeprotocol_class, vec![0, 6], 5).is_empty());
+        assert_eq!(batcher.poll(&soeprotocol_class, 9), None);
+        assert_eq!(
+            batcher.poll(&soeprotocol_class, 10),
+            Some(vec![0, 3, 4, 0, 21, 0, 1, 2, 0, 6])
+        );
+        assert_eq!(batcher.get_queued_count(), 0)
+    }
+
+    #[test]
+    fn batcher_single_packet_test() {
+        // a lone ping goes out as is, plain packets never get a crc
+        let soeprotocol_class = Soeprotocol::initialize(true, 0);
+        let mut batcher = super::MultiPacketBatcher::new(512, 10);
+        batcher.push(&soeprotocol_class, vec![0, 6], 0);
+        assert_eq!(batcher.flush(&soeprotocol_class), Some(vec![0, 6]))
+    }
+
+    #[test]
+    fn batcher_size_trigger_test() {
+        // room for the multi header and two 5 bytes sub packets
+        let soeprotocol_class = Soeprotocol::initialize(true, 0);
+        let mut batcher = super::MultiPacketBatcher::new(16, 10);
+        assert!(batcher
+            .push(&soeprotocol_class, vec![0, 9, 0, 0, 1], 0)
+            .is_empty());
+        assert!(batcher
+            .push(&soeprotocol_class, vec![0, 9, 0, 1, 2], 0)
+            .is_empty());
+        let frames = batcher.push(&soeprotocol_class, vec![0, 9, 0, 2, 3], 0);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), 16);
+        assert!(matches!(
+            soeprotocol_class.parse_packet(&frames[0]),
+            Ok(SoePacket::Multi(sub_packets)) if sub_packets.len() == 2
+        ))
+    }
+
+    #[test]
+    fn batcher_big_packet_test() {
+        let soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut batcher = super::MultiPacketBatcher::new(512, 10);
+        batcher.push(&soeprotocol_class, vec![0, 21, 0, 1], 0);
+        let big_packet = vec![0; 300];
+        let frames = batcher.push(&soeprotocol_class, big_packet.clone(), 0);
+        assert_eq!(frames, vec![vec![0, 21, 0, 1], big_packet]);
+        assert_eq!(batcher.get_queued_count(), 0)
+    }
+
+    #[test]
+    fn batcher_crc_test() {
+        let soeprotocol_class = Soeprotocol::initialize(true, 0);
+        let mut batcher = super::MultiPacketBatcher::new(512, 0);
+        batcher.push(&soeprotocol_class, vec![0, 21, 0, 1], 0);
+        batcher.push(&soeprotocol_class, vec![0, 21, 0, 2], 0);
+        let frame = batcher.poll(&soeprotocol_class, 0).unwrap();
+        assert_eq!(
+            soeprotocol_class.parse_packet(&frame),
+            Ok(SoePacket::Multi(vec![
+                SoePacket::Ack(AckPacket::new(1)),
+                SoePacket::Ack(AckPacket::new(2)),
+            ]))
+        )
+    }
+
+    #[test]
+    fn batcher_compression_encryption_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(true, 7);
+        soeprotocol_class.enable_compression(100);
+        soeprotocol_class.set_encrypt_method(4).unwrap();
+        // the compression flag byte takes the place of the last sub packet
+        let mut batcher = super::MultiPacketBatcher::new(17, 0);
+        batcher.push(&soeprotocol_class, vec![0, 9, 0, 0, 1], 0);
+        batcher.push(&soeprotocol_class, vec![0, 9, 0, 1, 2], 0);
+        let frames = batcher.push(&soeprotocol_class, vec![0, 21, 0, 1], 0);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].len() <= 17);
+        assert!(matches!(
+            soeprotocol_class.parse_packet(&frames[0]),
+            Ok(SoePacket::Multi(sub_packets)) if sub_packets.len() == 2
+        ));
+        let frame = batcher.poll(&soeprotocol_class, 0).unwrap();
+        assert_eq!(
+            soeprotocol_class.parse_packet(&frame),
+            Ok(SoePacket::Ack(AckPacket::new(1)))
+        )
+    }
+}
@@ -0,0 +1,234 @@
+use super::protocol_errors::ProtocolError;
+use wasm_bindgen::prelude::*;
+
+// the first fragment of a reliable payload starts with the total length
+const FRAGMENT_HEADER_SIZE: usize = 4;
+// opcode + sequence
+const RELIABLE_HEADER_SIZE: usize = 4;
+// the total length comes from the peer, bigger payloads are refused
+pub const DEFAULT_MAX_ASSEMBLED_SIZE: usize = 0x400000;
+
+pub fn max_reliable_data_size(udp_length: u32, crc_length: u8) -> usize {
+    (udp_length as usize).saturating_sub(RELIABLE_HEADER_SIZE + crc_length as usize)
+}
+
+// split a reliable payload into fragments data, the first one prefixed by the total length
+pub fn split_reliable_data(data: &[u8], max_data_size: usize) -> Vec<Vec<u8>> {
+    let first_fragment_size = max_data_size - FRAGMENT_HEADER_SIZE;
+    let mut first_fragment = Vec::with_capacity(max_data_size);
+    first_fragment.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    first_fragment.extend_from_slice(&data[..first_fragment_size]);
+    let mut fragments = vec![first_fragment];
+    for chunk in data[first_fragment_size..].chunks(max_data_size) {
+        fragments.push(chunk.to_vec());
+    }
+    fragments
+}
+
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct FragmentAssembler {
+    buffer: Vec<u8>,
+    total_length: Option<usize>,
+    next_sequence: u16,
+    max_length: usize,
+}
+
+impl Default for FragmentAssembler {
+    fn default() -> Self {
+        Self {
+            buffer: vec![],
+            total_length: None,
+            next_sequence: 0,
+            max_length: DEFAULT_MAX_ASSEMBLED_SIZE,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl FragmentAssembler {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns the full payload once the last fragment arrived
+    pub fn add_fragment(
+        &mut self,
+        data: &[u8],
+        sequence: u16,
+    ) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let result = self._add_fragment(data, sequence);
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    pub fn is_assembling(&self) -> bool {
+        self.total_length.is_some()
+    }
+
+    pub fn get_total_length(&self) -> Option<u32> {
+        self.total_length.map(|total_length| total_length as u32)
+    }
+
+    pub fn get_received_length(&self) -> u32 {
+        self.buffer.len() as u32
+    }
+
+    pub fn get_max_length(&self) -> u32 {
+        self.max_length as u32
+    }
+
+    pub fn set_max_length(&mut self, max_length: u32) {
+        self.max_length = max_length as usize;
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.total_length = None;
+    }
+}
+
+impl FragmentAssembler {
+    fn _add_fragment(
+        &mut self,
+        data: &[u8],
+        sequence: u16,
+    ) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let fragment_data = match self.total_length {
+            None => {
+                if data.len() < FRAGMENT_HEADER_SIZE {
+                    return Err(ProtocolError::Size { size: data.len() });
+                }
+                let total_length =
+                    u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if total_length > self.max_length {
+                    return Err(ProtocolError::Size { size: total_length });
+                }
+                // the buffer grows with the fragments, a lying header can't make us allocate
+                self.total_length = Some(total_length);
+                &data[FRAGMENT_HEADER_SIZE..]
+            }
+            Some(_) => {
+                if sequence != self.next_sequence {
+                    return Err(ProtocolError::Sequence {
+                        expected: self.next_sequence,
+                        given: sequence,
+                    });
+                }
+                data
+            }
+        };
+        self.next_sequence = sequence.wrapping_add(1);
+        let total_length = self.total_length.unwrap_or_default();
+        let received = self.buffer.len() + fragment_data.len();
+        if received > total_length {
+            return Err(ProtocolError::FragmentOverrun {
+                expected: total_length,
+                received,
+            });
+        }
+        self.buffer.extend_from_slice(fragment_data);
+        if received == total_length {
+            self.total_length = None;
+            return Ok(Some(std::mem::take(&mut self.buffer)));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::protocol_errors::ProtocolError;
+
+    #[test]
+    fn split_reliable_data_test() {
+        let data: Vec<u8> = (0..10).collect();
+        assert_eq!(
+            super::split_reliable_data(&data, 6),
+            vec![vec![0, 0, 0, 10, 0, 1], vec![2, 3, 4, 5, 6, 7], vec![8, 9]]
+        )
+    }
+
+    #[test]
+    fn split_then_assemble_test() {
+        let data: Vec<u8> = (0..=255).cycle().take(1500).collect();
+        let max_data_size = super::max_reliable_data_size(512, 2);
+        let mut assembler = super::FragmentAssembler::new();
+        let mut result = None;
+        for (sequence, fragment) in super::split_reliable_data(&data, max_data_size)
+            .iter()
+            .enumerate()
+        {
+            assert!(fragment.len() <= max_data_size);
+            result = assembler.add_fragment(fragment, sequence as u16).unwrap();
+        }
+        assert_eq!(result, Some(data))
+    }
+
+    #[test]
+    fn fragment_assembler_test() {
+        let mut assembler = super::FragmentAssembler::new();
+        assert_eq!(assembler.add_fragment(&[0, 0, 0, 5, 1, 2], 10), Ok(None));
+        assert!(assembler.is_assembling());
+        assert_eq!(assembler.add_fragment(&[3, 4], 11), Ok(None));
+        assert_eq!(
+            assembler.add_fragment(&[5], 12),
+            Ok(Some(vec![1, 2, 3, 4, 5]))
+        );
+        assert!(!assembler.is_assembling())
+    }
+
+    #[test]
+    fn fragment_assembler_sequence_wrap_test() {
+        let mut assembler = super::FragmentAssembler::new();
+        assert_eq!(assembler.add_fragment(&[0, 0, 0, 2, 1], 65535), Ok(None));
+        assert_eq!(assembler.add_fragment(&[2], 0), Ok(Some(vec![1, 2])));
+    }
+
+    #[test]
+    fn fragment_assembler_overrun_test() {
+        let mut assembler = super::FragmentAssembler::new();
+        assert_eq!(assembler.add_fragment(&[0, 0, 0, 3, 1, 2], 0), Ok(None));
+        assert_eq!(
+            assembler.add_fragment(&[3, 4], 1),
+            Err(ProtocolError::FragmentOverrun {
+                expected: 3,
+                received: 4
+            })
+        );
+        assert!(!assembler.is_assembling())
+    }
+
+    #[test]
+    fn fragment_assembler_max_length_test() {
+        let mut assembler = super::FragmentAssembler::new();
+        assert_eq!(
+            assembler.add_fragment(&[255, 255, 255, 255, 1], 0),
+            Err(ProtocolError::Size { size: 0xffffffff })
+        );
+        assert!(!assembler.is_assembling());
+        assembler.set_max_length(4);
+        assert_eq!(
+            assembler.add_fragment(&[0, 0, 0, 5, 1], 0),
+            Err(ProtocolError::Size { size: 5 })
+        );
+        assert_eq!(assembler.add_fragment(&[0, 0, 0, 4, 1, 2], 0), Ok(None));
+        assert_eq!(assembler.get_received_length(), 2)
+    }
+
+    #[test]
+    fn fragment_assembler_sequence_gap_test() {
+        let mut assembler = super::FragmentAssembler::new();
+        assert_eq!(assembler.add_fragment(&[0, 0, 0, 3, 1], 0), Ok(None));
+        assert_eq!(
+            assembler.add_fragment(&[2, 3], 2),
+            Err(ProtocolError::Sequence {
+                expected: 1,
+                given: 2
+            })
+        )
+    }
+}
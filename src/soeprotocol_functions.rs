@@ -1,57 +1,46 @@
+use super::crc::{crc32_legacy, crc_mask};
+use super::protocol_errors::ProtocolError;
+use super::soeprotocol::{DisconnectReason, EncryptMethod};
 use super::soeprotocol_packets_structs::*;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::Cursor;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Cursor, Read, Write};
+
+pub const COMPRESSION_FLAG_NONE: u8 = 0x00;
+pub const COMPRESSION_FLAG_ZLIB: u8 = 0x01;
+// inflated payloads bigger than this are treated as corrupted
+pub const MAX_INFLATED_SIZE: usize = 0x10000;
 
 pub enum PacketsMinSize {
     SessionRequest = 14,
     SessionReply = 21,
-    Disconnect = 6,
+    Disconnect = 8,
     NetStatusPacket = 42,
     MultiPacket = 7,
     DataPacket = 5,
     Ack = 4,
 }
 
-pub fn check_min_size(rdr: &Cursor<&std::vec::Vec<u8>>, min_size: usize, use_crc: bool) -> bool {
-    if use_crc {
-        return rdr.get_ref().len() >= min_size + 2;
-    } else {
-        return rdr.get_ref().len() >= min_size;
-    }
+// opcode, error code and the message nul terminator
+pub const FATAL_ERROR_MIN_SIZE: usize = 5;
+
+pub fn check_min_size(rdr: &Cursor<&[u8]>, min_size: usize, crc_length: u8) -> bool {
+    rdr.get_ref().len() >= min_size + crc_length as usize
 }
 
 pub fn disconnect_reason_to_string(reason_id: u16) -> String {
-    match reason_id {
-        0 => "DisconnectReasonIcmpError".to_string(),
-        1 => "DisconnectReasonTimeout".to_string(),
-        2 => "DisconnectReasonNone".to_string(),
-        3 => "DisconnectReasonOtherSideTerminated".to_string(),
-        4 => "DisconnectReasonManagerDeleted".to_string(),
-        5 => "DisconnectReasonConnectFail".to_string(),
-        6 => "DisconnectReasonApplication".to_string(),
-        7 => "DisconnectReasonUnreachableConnection".to_string(),
-        8 => "DisconnectReasonUnacknowledgedTimeout".to_string(),
-        9 => "DisconnectReasonNewConnectionAttempt".to_string(),
-        10 => "DisconnectReasonConnectionRefused".to_string(),
-        11 => "DisconnectReasonConnectErro".to_string(),
-        12 => "DisconnectReasonConnectingToSelf".to_string(),
-        13 => "DisconnectReasonReliableOverflow".to_string(),
-        14 => "DisconnectReasonApplicationReleased".to_string(),
-        15 => "DisconnectReasonCorruptPacket".to_string(),
-        16 => "DisconnectReasonProtocolMismatch".to_string(),
-        _ => "unknown".to_string(),
-    }
-}
-
-pub fn get_data_end(rdr: &Cursor<&std::vec::Vec<u8>>, use_crc: bool) -> u64 {
-    if use_crc {
-        return (rdr.get_ref().len() as u64) - 2_u64;
-    } else {
-        return rdr.get_ref().len() as u64;
-    };
+    DisconnectReason::from_code(reason_id)
+        .map_or("unknown", |reason| reason.get_name())
+        .to_string()
+}
+
+pub fn get_data_end(rdr: &Cursor<&[u8]>, crc_length: u8) -> u64 {
+    (rdr.get_ref().len() as u64) - crc_length as u64
 }
 
-pub fn write_data_length(wtr: &mut Vec<u8>, data_length: usize) {
+pub fn write_data_length<W: Write>(wtr: &mut W, data_length: usize) {
     if data_length <= 0xFF {
         wtr.write_u8(data_length as u8).unwrap_or_default();
     } else if data_length <= 0xFFFF {
@@ -63,7 +52,55 @@ pub fn write_data_length(wtr: &mut Vec<u8>, data_length: usize) {
     }
 }
 
-pub fn read_data_length(rdr: &mut Cursor<&std::vec::Vec<u8>>) -> u32 {
+// number of bytes written by write_data_length
+pub fn data_length_size(data_length: usize) -> usize {
+    if data_length <= 0xFF {
+        1
+    } else if data_length <= 0xFFFF {
+        2
+    } else {
+        4
+    }
+}
+
+pub fn write_sub_packets<'a, W: Write>(
+    wtr: &mut W,
+    opcode: u16,
+    sub_packets: impl IntoIterator<Item = &'a [u8]>,
+) -> std::io::Result<()> {
+    wtr.write_u16::<BigEndian>(opcode)?;
+    for sub_packet in sub_packets {
+        write_data_length(wtr, sub_packet.len());
+        wtr.write_all(sub_packet)?;
+    }
+    Ok(())
+}
+
+pub fn sub_packets_len<'a>(sub_packets: impl IntoIterator<Item = &'a [u8]>) -> usize {
+    2 + sub_packets
+        .into_iter()
+        .map(|sub_packet| data_length_size(sub_packet.len()) + sub_packet.len())
+        .sum::<usize>()
+}
+
+// data, fragment and ordered packets only differ by their opcode
+pub fn write_data_packet<W: Write>(
+    wtr: &mut W,
+    opcode: u16,
+    sequence: u16,
+    data: &[u8],
+) -> std::io::Result<()> {
+    wtr.write_u16::<BigEndian>(opcode)?;
+    wtr.write_u16::<BigEndian>(sequence)?;
+    wtr.write_all(data)
+}
+
+pub fn write_ack_packet<W: Write>(wtr: &mut W, opcode: u16, sequence: u16) -> std::io::Result<()> {
+    wtr.write_u16::<BigEndian>(opcode)?;
+    wtr.write_u16::<BigEndian>(sequence)
+}
+
+pub fn read_data_length(rdr: &mut Cursor<&[u8]>) -> u32 {
     let initial_rdr_position = rdr.position();
     let mut data_length: u32 = rdr.read_u8().unwrap_or_default() as u32;
     if data_length > 0xFF {
@@ -78,22 +115,129 @@ pub fn read_data_length(rdr: &mut Cursor<&std::vec::Vec<u8>>) -> u32 {
 }
 
 pub fn extract_subpacket_data(
-    rdr: &Cursor<&std::vec::Vec<u8>>,
+    rdr: &Cursor<&[u8]>,
     data_start_position: u64,
     sub_packet_data_length: u32,
 ) -> Vec<u8> {
-    let full_data_vec = rdr.get_ref().to_vec();
-    full_data_vec[data_start_position as usize
+    rdr.get_ref()[data_start_position as usize
         ..(data_start_position + sub_packet_data_length as u64) as usize]
         .to_vec()
 }
 
+pub fn verify_crc(
+    data: &[u8],
+    data_end: usize,
+    crc_seed: u32,
+    crc_length: u8,
+) -> Result<(), ProtocolError> {
+    let given_crc = data[data_end..data_end + crc_length as usize]
+        .iter()
+        .fold(0, |crc, byte| (crc << 8) | *byte as u32);
+    let expected_crc = crc32_legacy(&data[..data_end], crc_seed as usize) & crc_mask(crc_length);
+    if expected_crc != given_crc {
+        return Err(ProtocolError::Crc {
+            expected: expected_crc,
+            given: given_crc,
+        });
+    }
+    Ok(())
+}
+
 pub fn write_packet_data(wtr: &mut Vec<u8>, data_packet: &mut DataPacket) {
     wtr.write_u16::<BigEndian>(data_packet.sequence)
         .unwrap_or_default();
     wtr.append(data_packet.get_data());
 }
 
+// returns the compression flag followed by the payload, deflated if that makes it smaller
+pub fn compress_payload(payload: &[u8], threshold: usize) -> Vec<u8> {
+    if payload.len() > threshold {
+        let mut encoder = ZlibEncoder::new(vec![COMPRESSION_FLAG_ZLIB], Compression::default());
+        if encoder.write_all(payload).is_ok() {
+            if let Ok(compressed) = encoder.finish() {
+                if compressed.len() <= payload.len() {
+                    return compressed;
+                }
+            }
+        }
+    }
+    let mut uncompressed = Vec::with_capacity(payload.len() + 1);
+    uncompressed.push(COMPRESSION_FLAG_NONE);
+    uncompressed.extend_from_slice(payload);
+    uncompressed
+}
+
+// reads the compression flag and returns the inflated payload
+pub fn inflate_payload(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    match data.first() {
+        Some(&COMPRESSION_FLAG_NONE) => Ok(data[1..].to_vec()),
+        Some(&COMPRESSION_FLAG_ZLIB) => {
+            let mut payload = vec![];
+            ZlibDecoder::new(&data[1..])
+                .take(MAX_INFLATED_SIZE as u64 + 1)
+                .read_to_end(&mut payload)
+                .map_err(|error| ProtocolError::Compression(error.to_string()))?;
+            if payload.len() > MAX_INFLATED_SIZE {
+                return Err(ProtocolError::Compression(format!(
+                    "inflated payload exceeds {} bytes",
+                    MAX_INFLATED_SIZE
+                )));
+            }
+            Ok(payload)
+        }
+        Some(flag) => Err(ProtocolError::Compression(format!(
+            "unknown compression flag {}",
+            flag
+        ))),
+        None => Err(ProtocolError::Size { size: 0 }),
+    }
+}
+
+// each 4 bytes block is xored with the previous encrypted block, starting with the crc seed
+fn xor_chain(data: &mut [u8], crc_seed: u32, decrypt: bool) {
+    let mut key = crc_seed.to_be_bytes();
+    let mut blocks = data.chunks_exact_mut(4);
+    for block in &mut blocks {
+        let previous: [u8; 4] = block.try_into().unwrap_or_default();
+        for (byte, key_byte) in block.iter_mut().zip(key) {
+            *byte ^= key_byte;
+        }
+        key = if decrypt {
+            previous
+        } else {
+            block.try_into().unwrap_or_default()
+        };
+    }
+    // leftover bytes only use the last key byte
+    for byte in blocks.into_remainder() {
+        *byte ^= key[3];
+    }
+}
+
+// the crc seed bytes are rolled over the whole body
+fn xor_buffer(data: &mut [u8], crc_seed: u32) {
+    let key = crc_seed.to_be_bytes();
+    for (byte, key_byte) in data.iter_mut().zip(key.iter().cycle()) {
+        *byte ^= key_byte;
+    }
+}
+
+pub fn encrypt_body(body: &mut [u8], encrypt_method: EncryptMethod, crc_seed: u32) {
+    match encrypt_method {
+        EncryptMethod::EncryptMethodXor => xor_chain(body, crc_seed, false),
+        EncryptMethod::EncryptMethodXorBuffer => xor_buffer(body, crc_seed),
+        _ => {}
+    }
+}
+
+pub fn decrypt_body(body: &mut [u8], encrypt_method: EncryptMethod, crc_seed: u32) {
+    match encrypt_method {
+        EncryptMethod::EncryptMethodXor => xor_chain(body, crc_seed, true),
+        EncryptMethod::EncryptMethodXorBuffer => xor_buffer(body, crc_seed),
+        _ => {}
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -111,4 +255,68 @@ mod tests {
             [0, 0, 2, 1, 1, 0, 0, 0, 1, 1, 3, 0, 0, 0, 115, 111, 101, 0, 0, 0, 0].to_vec()
         )
     }
+
+    #[test]
+    fn compress_payload_test() {
+        let payload = vec![7; 200];
+        let compressed = super::compress_payload(&payload, 100);
+        assert_eq!(compressed[0], super::COMPRESSION_FLAG_ZLIB);
+        assert!(compressed.len() < payload.len());
+        assert_eq!(super::inflate_payload(&compressed), Ok(payload.clone()));
+        let uncompressed = super::compress_payload(&payload[..50], 100);
+        assert_eq!(uncompressed[0], super::COMPRESSION_FLAG_NONE);
+        assert_eq!(
+            super::inflate_payload(&uncompressed),
+            Ok(payload[..50].to_vec())
+        )
+    }
+
+    #[test]
+    fn inflate_payload_error_test() {
+        assert!(matches!(
+            super::inflate_payload(&[1, 2, 3]),
+            Err(super::ProtocolError::Compression(_))
+        ));
+        assert!(matches!(
+            super::inflate_payload(&[9, 2, 3]),
+            Err(super::ProtocolError::Compression(_))
+        ))
+    }
+
+    #[test]
+    fn xor_chain_test() {
+        let body: Vec<u8> = (0..11).collect();
+        let mut encrypted = body.clone();
+        super::encrypt_body(
+            &mut encrypted,
+            super::EncryptMethod::EncryptMethodXor,
+            0x01020304,
+        );
+        assert_eq!(encrypted[..4], [1, 3, 1, 7]);
+        assert_eq!(encrypted[4..8], [5, 6, 7, 0]);
+        super::decrypt_body(
+            &mut encrypted,
+            super::EncryptMethod::EncryptMethodXor,
+            0x01020304,
+        );
+        assert_eq!(encrypted, body)
+    }
+
+    #[test]
+    fn xor_buffer_test() {
+        let body: Vec<u8> = (0..6).collect();
+        let mut encrypted = body.clone();
+        super::encrypt_body(
+            &mut encrypted,
+            super::EncryptMethod::EncryptMethodXorBuffer,
+            0x01020304,
+        );
+        assert_eq!(encrypted, [1, 3, 1, 7, 5, 7]);
+        super::decrypt_body(
+            &mut encrypted,
+            super::EncryptMethod::EncryptMethodXorBuffer,
+            0x01020304,
+        );
+        assert_eq!(encrypted, body)
+    }
 }
@@ -0,0 +1,286 @@
+use super::soeprotocol_packets_structs::{NetStatusReplyPacket, NetStatusRequestPacket};
+
+// per session traffic counters and net status update intervals, times in milliseconds
+#[derive(Debug, Default, Clone)]
+pub struct NetStatusStats {
+    packets_sent: u64,
+    packets_received: u64,
+    last_update_at: Option<u64>,
+    last_update: u32,
+    shortest_update: u32,
+    longest_update: u32,
+    total_updates: u64,
+    update_count: u64,
+    last_server_update: u32,
+}
+
+impl NetStatusStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_sent(&mut self) {
+        self.packets_sent += 1;
+    }
+
+    pub fn record_received(&mut self) {
+        self.packets_received += 1;
+    }
+
+    // time between two net status updates
+    pub fn record_update(&mut self, now: u64) {
+        if let Some(last_update_at) = self.last_update_at {
+            let interval = now.saturating_sub(last_update_at).min(u32::MAX as u64) as u32;
+            if self.update_count == 0 {
+                self.shortest_update = interval;
+                self.longest_update = interval;
+            } else {
+                self.shortest_update = self.shortest_update.min(interval);
+                self.longest_update = self.longest_update.max(interval);
+            }
+            self.last_update = interval;
+            self.total_updates += interval as u64;
+            self.update_count += 1;
+        }
+        self.last_update_at = Some(now);
+    }
+
+    // client side, the request sent now counts as an update
+    pub fn build_request(&mut self, now: u64) -> NetStatusRequestPacket {
+        self.record_update(now);
+        NetStatusRequestPacket::new(
+            now as u16,
+            self.last_update,
+            self.get_average_update(),
+            self.shortest_update,
+            self.longest_update,
+            self.last_server_update,
+            self.packets_sent,
+            self.packets_received,
+            0,
+        )
+    }
+
+    pub fn handle_reply(&mut self, reply: &NetStatusReplyPacket) {
+        self.last_server_update = reply.server_tick_count;
+    }
+
+    // server side, echo the client counters next to ours
+    pub fn build_reply(
+        &mut self,
+        request: &NetStatusRequestPacket,
+        now: u64,
+    ) -> NetStatusReplyPacket {
+        self.record_update(now);
+        NetStatusReplyPacket::new(
+            request.client_tick_count,
+            now as u32,
+            request.packets_sent,
+            request.packets_received,
+            self.packets_sent,
+            self.packets_received,
+            0,
+        )
+    }
+
+    pub fn get_packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    pub fn get_packets_received(&self) -> u64 {
+        self.packets_received
+    }
+
+    pub fn get_last_update(&self) -> u32 {
+        self.last_update
+    }
+
+    pub fn get_average_update(&self) -> u32 {
+        if self.update_count == 0 {
+            return 0;
+        }
+        (self.total_updates / self.update_count).min(u32::MAX as u64) as u32
+    }
+
+    pub fn get_shortest_update(&self) -> u32 {
+        self.shortest_update
+    }
+
+    pub fn get_longest_update(&self) -> u32 {
+        self.longest_update
+    }
+}
+
+// smoothing factors, the same as rfc 6298 for the rtt and rfc 3550 for the jitter
+const RTT_ALPHA: f64 = 0.125;
+const JITTER_GAIN: f64 = 0.0625;
+const OFFSET_ALPHA: f64 = 0.125;
+
+// the extended value closest to reference with the same low 32 bits as tick
+fn extend_tick(tick: u32, reference: u64) -> u64 {
+    let distance = tick.wrapping_sub(reference as u32) as i32 as i64;
+    let extended = reference as i64 + distance;
+    if extended < 0 {
+        (extended + (1 << 32)) as u64
+    } else {
+        extended as u64
+    }
+}
+
+// estimates latency and the client to server clock offset from net status exchanges
+#[derive(Debug, Default, Clone)]
+pub struct ClockSync {
+    srtt: Option<f64>,
+    jitter: f64,
+    last_rtt: Option<u64>,
+    offset: Option<f64>,
+    server_tick: Option<u64>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // client side, now is the local time the reply arrived at
+    // returns false when the reply doesn't answer this request
+    pub fn add_sample(
+        &mut self,
+        request: &NetStatusRequestPacket,
+        reply: &NetStatusReplyPacket,
+        now: u64,
+    ) -> bool {
+        if request.client_tick_count != reply.client_tick_count {
+            return false;
+        }
+        // the tick count is the low 16 bits of the local time the request was sent at
+        let rtt = (now as u16).wrapping_sub(reply.client_tick_count) as u64;
+        let sent_at = now.saturating_sub(rtt);
+        let server_tick = match self.server_tick {
+            Some(reference) => extend_tick(reply.server_tick_count, reference),
+            None => reply.server_tick_count as u64,
+        };
+        self.server_tick = Some(server_tick);
+        // the server stamped the reply half way through the round trip
+        let offset = server_tick as f64 - (sent_at as f64 + rtt as f64 / 2.0);
+        self.offset = Some(match self.offset {
+            Some(smoothed) => smoothed + OFFSET_ALPHA * (offset - smoothed),
+            None => offset,
+        });
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (1.0 - RTT_ALPHA) * srtt + RTT_ALPHA * rtt as f64,
+            None => rtt as f64,
+        });
+        if let Some(last_rtt) = self.last_rtt {
+            let variation = (rtt as f64 - last_rtt as f64).abs();
+            self.jitter += JITTER_GAIN * (variation - self.jitter);
+        }
+        self.last_rtt = Some(rtt);
+        true
+    }
+
+    pub fn get_srtt(&self) -> Option<u64> {
+        self.srtt.map(|srtt| srtt.round() as u64)
+    }
+
+    pub fn get_jitter(&self) -> u64 {
+        self.jitter.round() as u64
+    }
+
+    // server time minus client time, in milliseconds
+    pub fn get_offset(&self) -> Option<i64> {
+        self.offset.map(|offset| offset.round() as i64)
+    }
+
+    // server clock estimate for a local time
+    pub fn to_server_time(&self, now: u64) -> Option<u64> {
+        let server_time = now as i64 + self.get_offset()?;
+        Some(server_time.max(0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_status_request_test() {
+        let mut stats = NetStatusStats::new();
+        for _ in 0..3 {
+            stats.record_sent();
+        }
+        stats.record_received();
+        let request = stats.build_request(1000);
+        assert_eq!(request.last_client_update, 0);
+        assert_eq!(request.packets_sent, 3);
+        assert_eq!(request.packets_received, 1);
+        stats.build_request(1100);
+        let request = stats.build_request(1400);
+        assert_eq!(request.client_tick_count, 1400);
+        assert_eq!(request.last_client_update, 300);
+        assert_eq!(request.average_update, 200);
+        assert_eq!(request.shortest_update, 100);
+        assert_eq!(request.longest_update, 300)
+    }
+
+    #[test]
+    fn net_status_reply_test() {
+        let mut client = NetStatusStats::new();
+        let mut server = NetStatusStats::new();
+        client.record_sent();
+        server.record_received();
+        server.record_sent();
+        let request = client.build_request(70000);
+        let reply = server.build_reply(&request, 5000);
+        assert_eq!(reply.client_tick_count, 70000_u64 as u16);
+        assert_eq!(reply.server_tick_count, 5000);
+        assert_eq!(reply.client_packet_sent, 1);
+        assert_eq!(reply.client_packet_received, 0);
+        assert_eq!(reply.server_packet_sent, 1);
+        assert_eq!(reply.server_packet_received, 1);
+        client.handle_reply(&reply);
+        assert_eq!(client.build_request(71000).last_server_update, 5000)
+    }
+
+    #[test]
+    fn clock_sync_test() {
+        let mut client = NetStatusStats::new();
+        let mut server = NetStatusStats::new();
+        let mut clock_sync = ClockSync::new();
+        // the server clock runs 5000ms ahead
+        let request = client.build_request(1000);
+        let reply = server.build_reply(&request, 6050);
+        assert!(clock_sync.add_sample(&request, &reply, 1100));
+        assert_eq!(clock_sync.get_srtt(), Some(100));
+        assert_eq!(clock_sync.get_offset(), Some(5000));
+        assert_eq!(clock_sync.get_jitter(), 0);
+        assert_eq!(clock_sync.to_server_time(2000), Some(7000));
+        let request = client.build_request(2000);
+        let reply = server.build_reply(&request, 7100);
+        assert!(clock_sync.add_sample(&request, &reply, 2200));
+        assert_eq!(clock_sync.get_srtt(), Some(113));
+        assert_eq!(clock_sync.get_offset(), Some(5000));
+        assert_eq!(clock_sync.get_jitter(), 6);
+        // a reply to another request is ignored
+        let stale = client.build_request(3000);
+        assert!(!clock_sync.add_sample(&stale, &reply, 3100))
+    }
+
+    #[test]
+    fn clock_sync_wrap_test() {
+        let mut clock_sync = ClockSync::new();
+        let mut stats = NetStatusStats::new();
+        // client ticks wrap between the request and the reply
+        let request = stats.build_request(65500);
+        let reply =
+            NetStatusReplyPacket::new(request.client_tick_count, u32::MAX - 10, 0, 0, 0, 0, 0);
+        assert!(clock_sync.add_sample(&request, &reply, 65600));
+        assert_eq!(clock_sync.get_srtt(), Some(100));
+        let offset = clock_sync.get_offset().unwrap();
+        // server ticks wrap between two replies
+        let request = stats.build_request(66500);
+        let reply = NetStatusReplyPacket::new(request.client_tick_count, 989, 0, 0, 0, 0, 0);
+        assert!(clock_sync.add_sample(&request, &reply, 66600));
+        assert_eq!(clock_sync.get_offset(), Some(offset))
+    }
+}
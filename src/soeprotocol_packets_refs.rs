@@ -0,0 +1,555 @@
+use super::lib_utils::str_from_u8_nul_utf8_checked;
+use super::packing::{str_nul_utf8_unchecked_len, write_str_nul_utf8_unchecked, PackInto};
+use super::protocol_errors::ProtocolError;
+use super::soeprotocol::SoeOpcode;
+use super::soeprotocol_functions::*;
+use super::soeprotocol_packets_structs::*;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Write};
+
+// borrowed views over a datagram, nothing is copied until into_owned is called
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionRequestRef<'a> {
+    pub session_id: u32,
+    pub protocol_version: u32,
+    pub udp_length: u32,
+    pub protocol: &'a str,
+}
+
+impl SessionRequestRef<'_> {
+    pub fn into_owned(self) -> SessionRequestPacket {
+        SessionRequestPacket::new(
+            self.session_id,
+            self.protocol_version,
+            self.udp_length,
+            self.protocol.to_owned(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataRef<'a> {
+    pub sequence: u16,
+    // only set by the parser, from the opcode
+    channel: u8,
+    pub data: &'a [u8],
+}
+
+impl DataRef<'_> {
+    pub fn get_channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn into_owned(self) -> DataPacket {
+        let mut packet = DataPacket::new(self.data.to_vec(), self.sequence);
+        // parsed channels are always in range
+        packet.set_channel(self.channel).unwrap_or_default();
+        packet
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FatalErrorRef<'a> {
+    pub error_code: u16,
+    pub message: &'a str,
+}
+
+impl FatalErrorRef<'_> {
+    pub fn into_owned(self) -> FatalErrorPacket {
+        FatalErrorPacket::new(self.error_code, self.message.to_owned())
+    }
+}
+
+// multi and group packets, sub packets are parsed while iterating
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MultiRef<'a> {
+    data: &'a [u8],
+    data_end: usize,
+}
+
+impl<'a> MultiRef<'a> {
+    pub fn iter(&self) -> MultiIter<'a> {
+        let mut rdr = Cursor::new(self.data);
+        // skip the opcode
+        rdr.set_position(2);
+        MultiIter {
+            rdr,
+            data_end: self.data_end as u64,
+        }
+    }
+
+    pub fn into_owned(self) -> Result<Vec<SoePacket>, ProtocolError> {
+        self.iter()
+            .map(|sub_packet| sub_packet.and_then(SoePacketRef::into_owned))
+            .collect()
+    }
+}
+
+impl<'a> IntoIterator for MultiRef<'a> {
+    type Item = Result<SoePacketRef<'a>, ProtocolError>;
+    type IntoIter = MultiIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MultiIter<'a> {
+    rdr: Cursor<&'a [u8]>,
+    data_end: u64,
+}
+
+impl<'a> Iterator for MultiIter<'a> {
+    type Item = Result<SoePacketRef<'a>, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rdr.position() >= self.data_end {
+            return None;
+        }
+        let sub_packet_data_length = read_data_length(&mut self.rdr);
+        let sub_packet_start = self.rdr.position();
+        if sub_packet_data_length == 0
+            || sub_packet_data_length as u64 + sub_packet_start > self.data_end
+        {
+            // nothing after a corrupted sub packet can be trusted
+            self.rdr.set_position(self.data_end);
+            return Some(Err(ProtocolError::Corruption {
+                offset: sub_packet_start,
+                length: sub_packet_data_length,
+                data_end: self.data_end,
+            }));
+        }
+        let sub_packet_end = sub_packet_start + sub_packet_data_length as u64;
+        self.rdr.set_position(sub_packet_end);
+        let data: &'a [u8] = self.rdr.get_ref();
+        // sub packets never carry their own crc
+        Some(parse_packet_ref(
+            &data[sub_packet_start as usize..sub_packet_end as usize],
+            0,
+            0,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SoePacketRef<'a> {
+    SessionRequest(SessionRequestRef<'a>),
+    SessionReply(SessionReplyPacket),
+    Multi(MultiRef<'a>),
+    Group(MultiRef<'a>),
+    Disconnect(Option<DisconnectPacket>),
+    Ping,
+    NetStatusRequest(NetStatusRequestPacket),
+    NetStatusReply(NetStatusReplyPacket),
+    Data(DataRef<'a>),
+    DataFragment(DataRef<'a>),
+    OutOfOrder(AckPacket),
+    Ack(AckPacket),
+    Ordered(DataRef<'a>),
+    FatalError(FatalErrorRef<'a>),
+    Unknown(&'a [u8]),
+}
+
+impl SoePacketRef<'_> {
+    pub fn into_owned(self) -> Result<SoePacket, ProtocolError> {
+        Ok(match self {
+            SoePacketRef::SessionRequest(packet) => SoePacket::SessionRequest(packet.into_owned()),
+            SoePacketRef::SessionReply(packet) => SoePacket::SessionReply(packet),
+            SoePacketRef::Multi(packet) => SoePacket::Multi(packet.into_owned()?),
+            SoePacketRef::Group(packet) => SoePacket::Group(packet.into_owned()?),
+            SoePacketRef::Disconnect(packet) => SoePacket::Disconnect(packet),
+            SoePacketRef::Ping => SoePacket::Ping,
+            SoePacketRef::NetStatusRequest(packet) => SoePacket::NetStatusRequest(packet),
+            SoePacketRef::NetStatusReply(packet) => SoePacket::NetStatusReply(packet),
+            SoePacketRef::Data(packet) => SoePacket::Data(packet.into_owned()),
+            SoePacketRef::DataFragment(packet) => SoePacket::DataFragment(packet.into_owned()),
+            SoePacketRef::OutOfOrder(packet) => SoePacket::OutOfOrder(packet),
+            SoePacketRef::Ack(packet) => SoePacket::Ack(packet),
+            SoePacketRef::Ordered(packet) => SoePacket::Ordered(packet.into_owned()),
+            SoePacketRef::FatalError(packet) => SoePacket::FatalError(packet.into_owned()),
+            SoePacketRef::Unknown(raw) => SoePacket::Unknown(raw.to_vec()),
+        })
+    }
+}
+
+impl PackInto for SoePacketRef<'_> {
+    fn packed_len(&self) -> usize {
+        match self {
+            SoePacketRef::SessionRequest(packet) => {
+                14 + str_nul_utf8_unchecked_len(packet.protocol)
+            }
+            SoePacketRef::SessionReply(packet) => packet.packed_len(),
+            // sub packets are copied with their length prefixes
+            SoePacketRef::Multi(packet) | SoePacketRef::Group(packet) => packet.data_end,
+            SoePacketRef::Disconnect(Some(packet)) => packet.packed_len(),
+            SoePacketRef::Disconnect(None) | SoePacketRef::Ping => 2,
+            SoePacketRef::NetStatusRequest(packet) => packet.packed_len(),
+            SoePacketRef::NetStatusReply(packet) => packet.packed_len(),
+            SoePacketRef::Data(packet)
+            | SoePacketRef::DataFragment(packet)
+            | SoePacketRef::Ordered(packet) => 4 + packet.data.len(),
+            SoePacketRef::OutOfOrder(_) | SoePacketRef::Ack(_) => 4,
+            SoePacketRef::FatalError(packet) => 4 + str_nul_utf8_unchecked_len(packet.message),
+            SoePacketRef::Unknown(raw) => raw.len(),
+        }
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        match self {
+            SoePacketRef::SessionRequest(packet) => {
+                wtr.write_u16::<BigEndian>(SoeOpcode::SessionRequest as u16)?;
+                wtr.write_u32::<BigEndian>(packet.protocol_version)?;
+                wtr.write_u32::<BigEndian>(packet.session_id)?;
+                wtr.write_u32::<BigEndian>(packet.udp_length)?;
+                write_str_nul_utf8_unchecked(wtr, packet.protocol)
+            }
+            SoePacketRef::SessionReply(packet) => packet.write_to(wtr),
+            SoePacketRef::Multi(packet) => {
+                wtr.write_u16::<BigEndian>(SoeOpcode::MultiPacket as u16)?;
+                wtr.write_all(&packet.data[2..packet.data_end])
+            }
+            SoePacketRef::Group(packet) => {
+                wtr.write_u16::<BigEndian>(SoeOpcode::Group as u16)?;
+                wtr.write_all(&packet.data[2..packet.data_end])
+            }
+            SoePacketRef::Disconnect(Some(packet)) => packet.write_to(wtr),
+            SoePacketRef::Disconnect(None) => {
+                wtr.write_u16::<BigEndian>(SoeOpcode::Disconnect as u16)
+            }
+            SoePacketRef::Ping => wtr.write_u16::<BigEndian>(SoeOpcode::Ping as u16),
+            SoePacketRef::NetStatusRequest(packet) => packet.write_to(wtr),
+            SoePacketRef::NetStatusReply(packet) => packet.write_to(wtr),
+            SoePacketRef::Data(packet) => write_data_packet(
+                wtr,
+                SoeOpcode::Data.with_channel(packet.channel)?,
+                packet.sequence,
+                packet.data,
+            ),
+            SoePacketRef::DataFragment(packet) => write_data_packet(
+                wtr,
+                SoeOpcode::DataFragment.with_channel(packet.channel)?,
+                packet.sequence,
+                packet.data,
+            ),
+            SoePacketRef::OutOfOrder(packet) => write_ack_packet(
+                wtr,
+                SoeOpcode::OutOfOrder.with_channel(packet.get_channel())?,
+                packet.sequence,
+            ),
+            SoePacketRef::Ack(packet) => write_ack_packet(
+                wtr,
+                SoeOpcode::Ack.with_channel(packet.get_channel())?,
+                packet.sequence,
+            ),
+            SoePacketRef::Ordered(packet) => {
+                write_data_packet(wtr, SoeOpcode::Ordered as u16, packet.sequence, packet.data)
+            }
+            SoePacketRef::FatalError(packet) => {
+                wtr.write_u16::<BigEndian>(SoeOpcode::FatalError as u16)?;
+                wtr.write_u16::<BigEndian>(packet.error_code)?;
+                write_str_nul_utf8_unchecked(wtr, packet.message)
+            }
+            SoePacketRef::Unknown(raw) => wtr.write_all(raw),
+        }
+    }
+}
+
+// parse a plain packet, compression and encryption must already be removed
+pub fn parse_packet_ref(
+    data: &[u8],
+    crc_length: u8,
+    crc_seed: u32,
+) -> Result<SoePacketRef<'_>, ProtocolError> {
+    if data.len() < 2 {
+        return Ok(SoePacketRef::Unknown(data));
+    }
+    let mut rdr = Cursor::new(data);
+    let opcode = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    let channel = SoeOpcode::get_channel(opcode);
+    match SoeOpcode::from_code(opcode) {
+        SoeOpcode::SessionRequest => parse_session_request(rdr),
+        SoeOpcode::SessionReply => parse_session_reply(rdr),
+        SoeOpcode::MultiPacket => {
+            parse_sub_packets(rdr, crc_length, crc_seed).map(SoePacketRef::Multi)
+        }
+        SoeOpcode::Group => parse_sub_packets(rdr, crc_length, crc_seed).map(SoePacketRef::Group),
+        SoeOpcode::Disconnect => parse_disconnect(rdr, crc_length, crc_seed),
+        SoeOpcode::Ping => Ok(SoePacketRef::Ping),
+        SoeOpcode::NetStatusRequest => parse_net_status_request(rdr),
+        SoeOpcode::NetStatusReply => parse_net_status_reply(rdr),
+        SoeOpcode::Data => parse_data(rdr, crc_length, crc_seed, channel).map(SoePacketRef::Data),
+        SoeOpcode::DataFragment => {
+            parse_data(rdr, crc_length, crc_seed, channel).map(SoePacketRef::DataFragment)
+        }
+        SoeOpcode::OutOfOrder => {
+            parse_ack(rdr, crc_length, crc_seed, channel).map(SoePacketRef::OutOfOrder)
+        }
+        SoeOpcode::Ack => parse_ack(rdr, crc_length, crc_seed, channel).map(SoePacketRef::Ack),
+        SoeOpcode::Ordered => parse_data(rdr, crc_length, crc_seed, 0).map(SoePacketRef::Ordered),
+        SoeOpcode::FatalError => parse_fatal_error(rdr, crc_length, crc_seed),
+        SoeOpcode::Unknown => Ok(SoePacketRef::Unknown(data)),
+    }
+}
+
+fn parse_session_request(mut rdr: Cursor<&[u8]>) -> Result<SoePacketRef<'_>, ProtocolError> {
+    if !check_min_size(&rdr, PacketsMinSize::SessionRequest as usize, 0) {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let protocol_version = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let session_id = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let udp_length = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let protocol_data_position = rdr.position() as usize;
+    let raw_data = rdr.into_inner();
+    let protocol = str_from_u8_nul_utf8_checked(&raw_data[protocol_data_position..]);
+    Ok(SoePacketRef::SessionRequest(SessionRequestRef {
+        session_id,
+        protocol_version,
+        udp_length,
+        protocol,
+    }))
+}
+
+fn parse_session_reply(mut rdr: Cursor<&[u8]>) -> Result<SoePacketRef<'_>, ProtocolError> {
+    if rdr.get_ref().len() != PacketsMinSize::SessionReply as usize {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let session_id = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let crc_seed = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let crc_length = rdr.read_u8().unwrap_or_default();
+    let encrypt_method = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    let udp_length = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    Ok(SoePacketRef::SessionReply(SessionReplyPacket::new(
+        session_id,
+        crc_seed,
+        crc_length,
+        encrypt_method,
+        udp_length,
+    )))
+}
+
+fn parse_disconnect(
+    mut rdr: Cursor<&[u8]>,
+    crc_length: u8,
+    crc_seed: u32,
+) -> Result<SoePacketRef<'_>, ProtocolError> {
+    // some clients only send the opcode
+    if !check_min_size(&rdr, PacketsMinSize::Disconnect as usize, crc_length) {
+        return Ok(SoePacketRef::Disconnect(None));
+    }
+    if crc_length > 0 {
+        let data_end: u64 = get_data_end(&rdr, crc_length);
+        verify_crc(rdr.get_ref(), data_end as usize, crc_seed, crc_length)?;
+    }
+    let session_id = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let reason = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    Ok(SoePacketRef::Disconnect(Some(DisconnectPacket::new(
+        session_id, reason,
+    ))))
+}
+
+fn parse_fatal_error(
+    mut rdr: Cursor<&[u8]>,
+    crc_length: u8,
+    crc_seed: u32,
+) -> Result<SoePacketRef<'_>, ProtocolError> {
+    if !check_min_size(&rdr, FATAL_ERROR_MIN_SIZE, crc_length) {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let data_end: u64 = get_data_end(&rdr, crc_length);
+    if crc_length > 0 {
+        verify_crc(rdr.get_ref(), data_end as usize, crc_seed, crc_length)?;
+    }
+    let error_code = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    let message_data_position = rdr.position() as usize;
+    let raw_data = rdr.into_inner();
+    let message = str_from_u8_nul_utf8_checked(&raw_data[message_data_position..data_end as usize]);
+    Ok(SoePacketRef::FatalError(FatalErrorRef {
+        error_code,
+        message,
+    }))
+}
+
+fn parse_net_status_request(mut rdr: Cursor<&[u8]>) -> Result<SoePacketRef<'_>, ProtocolError> {
+    if rdr.get_ref().len() != PacketsMinSize::NetStatusPacket as usize {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let client_tick_count = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    let last_client_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let average_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let shortest_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let longest_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let last_server_update = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let packets_sent = rdr.read_u64::<BigEndian>().unwrap_or_default();
+    let packets_received = rdr.read_u64::<BigEndian>().unwrap_or_default();
+    let unknown_field = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    Ok(SoePacketRef::NetStatusRequest(NetStatusRequestPacket::new(
+        client_tick_count,
+        last_client_update,
+        average_update,
+        shortest_update,
+        longest_update,
+        last_server_update,
+        packets_sent,
+        packets_received,
+        unknown_field,
+    )))
+}
+
+fn parse_net_status_reply(mut rdr: Cursor<&[u8]>) -> Result<SoePacketRef<'_>, ProtocolError> {
+    if rdr.get_ref().len() != PacketsMinSize::NetStatusPacket as usize {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let client_tick_count = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    let server_tick_count = rdr.read_u32::<BigEndian>().unwrap_or_default();
+    let client_packet_sent = rdr.read_u64::<BigEndian>().unwrap_or_default();
+    let client_packet_received = rdr.read_u64::<BigEndian>().unwrap_or_default();
+    let server_packet_sent = rdr.read_u64::<BigEndian>().unwrap_or_default();
+    let server_packet_received = rdr.read_u64::<BigEndian>().unwrap_or_default();
+    let unknown_field = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    Ok(SoePacketRef::NetStatusReply(NetStatusReplyPacket::new(
+        client_tick_count,
+        server_tick_count,
+        client_packet_sent,
+        client_packet_received,
+        server_packet_sent,
+        server_packet_received,
+        unknown_field,
+    )))
+}
+
+// multi and group packets share the same length prefixed layout
+fn parse_sub_packets(
+    rdr: Cursor<&[u8]>,
+    crc_length: u8,
+    crc_seed: u32,
+) -> Result<MultiRef<'_>, ProtocolError> {
+    if !check_min_size(&rdr, PacketsMinSize::MultiPacket as usize, crc_length) {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let data_end: u64 = get_data_end(&rdr, crc_length);
+    if crc_length > 0 {
+        verify_crc(rdr.get_ref(), data_end as usize, crc_seed, crc_length)?;
+    }
+    Ok(MultiRef {
+        data: rdr.into_inner(),
+        data_end: data_end as usize,
+    })
+}
+
+fn parse_data(
+    mut rdr: Cursor<&[u8]>,
+    crc_length: u8,
+    crc_seed: u32,
+    channel: u8,
+) -> Result<DataRef<'_>, ProtocolError> {
+    if !check_min_size(&rdr, PacketsMinSize::DataPacket as usize, crc_length) {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let sequence = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    let data_end: u64 = get_data_end(&rdr, crc_length);
+    if crc_length > 0 {
+        verify_crc(rdr.get_ref(), data_end as usize, crc_seed, crc_length)?;
+    }
+    let raw_data = rdr.into_inner();
+    Ok(DataRef {
+        sequence,
+        channel,
+        data: &raw_data[4..data_end as usize],
+    })
+}
+
+fn parse_ack(
+    mut rdr: Cursor<&[u8]>,
+    crc_length: u8,
+    crc_seed: u32,
+    channel: u8,
+) -> Result<AckPacket, ProtocolError> {
+    if !check_min_size(&rdr, PacketsMinSize::Ack as usize, crc_length) {
+        return Err(ProtocolError::Size {
+            size: rdr.get_ref().len(),
+        });
+    }
+    let sequence = rdr.read_u16::<BigEndian>().unwrap_or_default();
+    if crc_length > 0 {
+        let data_end: u64 = get_data_end(&rdr, crc_length);
+        verify_crc(rdr.get_ref(), data_end as usize, crc_seed, crc_length)?;
+    }
+    let mut packet = AckPacket::new(sequence);
+    packet.set_channel(channel)?;
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::crc::append_crc;
+    use super::*;
+
+    #[test]
+    fn parse_data_ref_test() {
+        let data = [0, 9, 0, 5, 1, 2, 3];
+        let packet = parse_packet_ref(&data, 0, 0).unwrap();
+        let SoePacketRef::Data(data_ref) = packet else {
+            panic!("expected data, got {:?}", packet)
+        };
+        assert_eq!(data_ref.sequence, 5);
+        // the payload points into the datagram
+        assert_eq!(data_ref.data.as_ptr(), data[4..].as_ptr());
+        assert_eq!(data_ref.into_owned(), DataPacket::new(vec![1, 2, 3], 5))
+    }
+
+    #[test]
+    fn parse_multi_ref_test() {
+        let mut data = vec![0, 3, 4, 0, 21, 0, 1, 5, 0, 9, 0, 2, 7];
+        append_crc(&mut data, 3);
+        let packet = parse_packet_ref(&data, 2, 3).unwrap();
+        let SoePacketRef::Multi(multi_ref) = packet else {
+            panic!("expected multi, got {:?}", packet)
+        };
+        let sub_packets: Vec<SoePacketRef> = multi_ref.iter().map(Result::unwrap).collect();
+        assert_eq!(sub_packets[0], SoePacketRef::Ack(AckPacket::new(1)));
+        let SoePacketRef::Data(data_ref) = sub_packets[1] else {
+            panic!("expected data, got {:?}", sub_packets[1])
+        };
+        assert_eq!(data_ref.data.as_ptr(), data[12..].as_ptr());
+        assert_eq!(sub_packets.len(), 2)
+    }
+
+    #[test]
+    fn parse_multi_ref_corruption_test() {
+        let data = [0, 3, 2, 0, 21, 9, 0, 9];
+        let SoePacketRef::Multi(multi_ref) = parse_packet_ref(&data, 0, 0).unwrap() else {
+            panic!("expected multi")
+        };
+        let mut sub_packets = multi_ref.iter();
+        assert!(matches!(
+            sub_packets.next(),
+            Some(Err(ProtocolError::Size { .. }))
+        ));
+        assert_eq!(
+            sub_packets.next(),
+            Some(Err(ProtocolError::Corruption {
+                offset: 6,
+                length: 9,
+                data_end: 8,
+            }))
+        );
+        assert_eq!(sub_packets.next(), None);
+        assert!(multi_ref.into_owned().is_err())
+    }
+}
@@ -1,7 +1,16 @@
-use serde::{Deserialize, Serialize};
+use super::packing::{str_nul_utf8_unchecked_len, write_str_nul_utf8_unchecked, PackInto};
+use super::protocol_errors::ProtocolError;
+use super::soeprotocol::{check_channel, SoeOpcode};
+use super::soeprotocol_functions::{
+    data_length_size, disconnect_reason_to_string, write_ack_packet, write_data_length,
+    write_data_packet, PacketsMinSize,
+};
+use byteorder::{BigEndian, WriteBytesExt};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use std::io::Write;
 use wasm_bindgen::prelude::*;
 #[wasm_bindgen]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SessionRequestPacket {
     pub session_id: u32,
     pub protocol_version: u32,
@@ -34,7 +43,7 @@ impl SessionRequestPacket {
 }
 
 #[wasm_bindgen]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SessionReplyPacket {
     pub session_id: u32,
     pub crc_seed: u32,
@@ -78,7 +87,7 @@ impl SessionReplyPacket {
 }
 
 #[wasm_bindgen]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NetStatusReplyPacket {
     pub client_tick_count: u16,
     pub server_tick_count: u32,
@@ -142,46 +151,123 @@ pub struct MultiPackablePacket {
     pub sequence: u16,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[wasm_bindgen]
 pub struct DataPacket {
     data: Vec<u8>,
     pub sequence: u16,
+    #[serde(default, deserialize_with = "deserialize_channel")]
+    channel: u8,
 }
 #[wasm_bindgen]
 impl DataPacket {
     #[wasm_bindgen(constructor)]
     pub fn new(data: Vec<u8>, sequence: u16) -> Self {
-        Self { data, sequence }
+        Self {
+            data,
+            sequence,
+            channel: 0,
+        }
     }
     pub fn get_sequence(&self) -> u16 {
         self.sequence
     }
+    pub fn get_channel(&self) -> u8 {
+        self.channel
+    }
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), ProtocolError> {
+        self.channel = check_channel(channel)?;
+        Ok(())
+    }
 }
 impl DataPacket {
     pub fn get_data(&mut self) -> &mut Vec<u8> {
         &mut self.data
     }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
 }
 
 #[wasm_bindgen]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct AckPacket {
     pub sequence: u16,
+    #[serde(default, deserialize_with = "deserialize_channel")]
+    channel: u8,
 }
 #[wasm_bindgen]
 impl AckPacket {
     #[wasm_bindgen(constructor)]
     pub fn new(sequence: u16) -> Self {
-        Self { sequence }
+        Self {
+            sequence,
+            channel: 0,
+        }
     }
     pub fn get_sequence(&self) -> u16 {
         self.sequence
     }
+    pub fn get_channel(&self) -> u8 {
+        self.channel
+    }
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), ProtocolError> {
+        self.channel = check_channel(channel)?;
+        Ok(())
+    }
 }
 
 #[wasm_bindgen]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DisconnectPacket {
+    pub session_id: u32,
+    pub reason: u16,
+}
+#[wasm_bindgen]
+impl DisconnectPacket {
+    #[wasm_bindgen(constructor)]
+    pub fn new(session_id: u32, reason: u16) -> Self {
+        Self { session_id, reason }
+    }
+    pub fn get_session_id(&self) -> u32 {
+        self.session_id
+    }
+    pub fn get_reason(&self) -> u16 {
+        self.reason
+    }
+    pub fn get_reason_name(&self) -> String {
+        disconnect_reason_to_string(self.reason)
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FatalErrorPacket {
+    pub error_code: u16,
+    message: String,
+}
+#[wasm_bindgen]
+impl FatalErrorPacket {
+    #[wasm_bindgen(constructor)]
+    pub fn new(error_code: u16, message: String) -> Self {
+        Self {
+            error_code,
+            message,
+        }
+    }
+    pub fn get_error_code(&self) -> u16 {
+        self.error_code
+    }
+    pub fn get_message(&self) -> String {
+        self.message.clone()
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NetStatusRequestPacket {
     pub client_tick_count: u16,
     pub last_client_update: u32,
@@ -196,6 +282,7 @@ pub struct NetStatusRequestPacket {
 #[wasm_bindgen]
 impl NetStatusRequestPacket {
     #[wasm_bindgen(constructor)]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         client_tick_count: u16,
         last_client_update: u32,
@@ -294,6 +381,11 @@ impl SubBasePackets {
         }
     }
 }
+impl Default for SubBasePackets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl SubBasePackets {
     pub fn add_sub_packet(&mut self, sub_packet: Vec<u8>) {
         self.sub_packets.push(sub_packet);
@@ -302,3 +394,339 @@ impl SubBasePackets {
         &self.sub_packets
     }
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SoePacket {
+    SessionRequest(SessionRequestPacket),
+    SessionReply(SessionReplyPacket),
+    Multi(Vec<SoePacket>),
+    Group(Vec<SoePacket>),
+    // a bare disconnect (opcode only) carries no session id nor reason
+    Disconnect(Option<DisconnectPacket>),
+    Ping,
+    NetStatusRequest(NetStatusRequestPacket),
+    NetStatusReply(NetStatusReplyPacket),
+    Data(DataPacket),
+    DataFragment(DataPacket),
+    OutOfOrder(AckPacket),
+    Ack(AckPacket),
+    // the sequence field holds the order
+    Ordered(DataPacket),
+    FatalError(FatalErrorPacket),
+    Unknown(Vec<u8>),
+}
+
+// refuse the channels that would pack as another opcode
+fn deserialize_channel<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    check_channel(u8::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+// channel 0 is left out so the output stays the same for single channel sessions
+fn channel_to_json(channel: u8) -> String {
+    if channel == 0 {
+        String::new()
+    } else {
+        format!(r#""channel":{},"#, channel)
+    }
+}
+
+fn sub_packets_to_json(name: &str, sub_packets: &[SoePacket]) -> String {
+    let sub_packets: Vec<String> = sub_packets.iter().map(|packet| packet.to_json()).collect();
+    format!(
+        r#"{{"name":"{}","sub_packets":[{}]}}"#,
+        name,
+        sub_packets.join(",")
+    )
+}
+
+impl SoePacket {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            SoePacket::SessionRequest(_) => "SessionRequest",
+            SoePacket::SessionReply(_) => "SessionReply",
+            SoePacket::Multi(_) => "MultiPacket",
+            SoePacket::Group(_) => "Group",
+            SoePacket::Disconnect(_) => "Disconnect",
+            SoePacket::Ping => "Ping",
+            SoePacket::NetStatusRequest(_) => "NetStatusRequest",
+            SoePacket::NetStatusReply(_) => "NetStatusReply",
+            SoePacket::Data(_) => "Data",
+            SoePacket::DataFragment(_) => "DataFragment",
+            SoePacket::OutOfOrder(_) => "OutOfOrder",
+            SoePacket::Ack(_) => "Ack",
+            SoePacket::Ordered(_) => "Ordered",
+            SoePacket::FatalError(_) => "FatalError",
+            SoePacket::Unknown(_) => "Unknown",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        match self {
+            SoePacket::SessionRequest(packet) => format!(
+                r#"{{"name":"SessionRequest","protocol_version":{},"session_id":{},"udp_length":{},"protocol":{}}}"#,
+                packet.protocol_version,
+                packet.session_id,
+                packet.udp_length,
+                serde_json::to_string(&packet.protocol).unwrap_or_default()
+            ),
+            SoePacket::SessionReply(packet) => format!(
+                r#"{{"name":"SessionReply","session_id":{},"crc_seed":{},"crc_length":{},"encrypt_method":{},"udp_length":{}}}"#,
+                packet.session_id,
+                packet.crc_seed,
+                packet.crc_length,
+                packet.encrypt_method,
+                packet.udp_length
+            ),
+            SoePacket::Multi(sub_packets) => sub_packets_to_json("MultiPacket", sub_packets),
+            SoePacket::Group(sub_packets) => sub_packets_to_json("Group", sub_packets),
+            SoePacket::Disconnect(Some(packet)) => format!(
+                r#"{{"name":"Disconnect","session_id":{},"reason":"{}","reason_code":{}}}"#,
+                packet.session_id,
+                disconnect_reason_to_string(packet.reason),
+                packet.reason
+            ),
+            SoePacket::Disconnect(None) => {
+                r#"{"name":"Disconnect","session_id":null,"reason":"unknown"}"#.to_string()
+            }
+            SoePacket::Ping => r#"{"name":"Ping"}"#.to_string(),
+            SoePacket::NetStatusRequest(packet) => format!(
+                r#"{{"name":"NetStatusRequest","client_tick_count":{},"last_client_update":{},"average_update":{},"shortest_update":{},"longest_update":{},"last_server_update":{},"packets_sent":{},"packets_received":{},"unknown_field":{}}}"#,
+                packet.client_tick_count,
+                packet.last_client_update,
+                packet.average_update,
+                packet.shortest_update,
+                packet.longest_update,
+                packet.last_server_update,
+                packet.packets_sent,
+                packet.packets_received,
+                packet.unknown_field
+            ),
+            SoePacket::NetStatusReply(packet) => format!(
+                r#"{{"name":"NetStatusReply","client_tick_count":{},"server_tick_count":{},"client_packet_sent":{},"client_packet_received":{},"server_packet_sent":{},"server_packet_received":{},"unknown_field":{}}}"#,
+                packet.client_tick_count,
+                packet.server_tick_count,
+                packet.client_packet_sent,
+                packet.client_packet_received,
+                packet.server_packet_sent,
+                packet.server_packet_received,
+                packet.unknown_field
+            ),
+            SoePacket::Data(packet) => format!(
+                r#"{{"name":"Data",{}"sequence":{},"data":{:?}}}"#,
+                channel_to_json(packet.channel),
+                packet.sequence,
+                packet.data
+            ),
+            SoePacket::DataFragment(packet) => format!(
+                r#"{{"name":"DataFragment",{}"sequence":{},"data":{:?}}}"#,
+                channel_to_json(packet.channel),
+                packet.sequence,
+                packet.data
+            ),
+            SoePacket::OutOfOrder(packet) => format!(
+                r#"{{"name":"OutOfOrder",{}"sequence":{}}}"#,
+                channel_to_json(packet.channel),
+                packet.sequence
+            ),
+            SoePacket::Ack(packet) => format!(
+                r#"{{"name":"Ack",{}"sequence":{}}}"#,
+                channel_to_json(packet.channel),
+                packet.sequence
+            ),
+            SoePacket::Ordered(packet) => format!(
+                r#"{{"name":"Ordered","order":{},"data":{:?}}}"#,
+                packet.sequence, packet.data
+            ),
+            // the message comes from the peer, serde_json escapes it
+            SoePacket::FatalError(packet) => format!(
+                r#"{{"name":"FatalError","error_code":{},"message":{}}}"#,
+                packet.error_code,
+                serde_json::to_string(&packet.message).unwrap_or_default()
+            ),
+            SoePacket::Unknown(raw) => format!(r#"{{"name":"Unknown","raw":{:?}}}"#, raw),
+        }
+    }
+}
+
+impl PackInto for SessionRequestPacket {
+    fn packed_len(&self) -> usize {
+        14 + str_nul_utf8_unchecked_len(&self.protocol)
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u16::<BigEndian>(SoeOpcode::SessionRequest as u16)?;
+        wtr.write_u32::<BigEndian>(self.protocol_version)?;
+        wtr.write_u32::<BigEndian>(self.session_id)?;
+        wtr.write_u32::<BigEndian>(self.udp_length)?;
+        write_str_nul_utf8_unchecked(wtr, &self.protocol)
+    }
+}
+
+impl PackInto for SessionReplyPacket {
+    fn packed_len(&self) -> usize {
+        PacketsMinSize::SessionReply as usize
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u16::<BigEndian>(SoeOpcode::SessionReply as u16)?;
+        wtr.write_u32::<BigEndian>(self.session_id)?;
+        wtr.write_u32::<BigEndian>(self.crc_seed)?;
+        wtr.write_u8(self.crc_length)?;
+        wtr.write_u16::<BigEndian>(self.encrypt_method)?;
+        wtr.write_u32::<BigEndian>(self.udp_length)?;
+        wtr.write_u32::<BigEndian>(3)
+    }
+}
+
+impl PackInto for DisconnectPacket {
+    fn packed_len(&self) -> usize {
+        PacketsMinSize::Disconnect as usize
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u16::<BigEndian>(SoeOpcode::Disconnect as u16)?;
+        wtr.write_u32::<BigEndian>(self.session_id)?;
+        wtr.write_u16::<BigEndian>(self.reason)
+    }
+}
+
+impl PackInto for FatalErrorPacket {
+    fn packed_len(&self) -> usize {
+        4 + str_nul_utf8_unchecked_len(&self.message)
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u16::<BigEndian>(SoeOpcode::FatalError as u16)?;
+        wtr.write_u16::<BigEndian>(self.error_code)?;
+        write_str_nul_utf8_unchecked(wtr, &self.message)
+    }
+}
+
+impl PackInto for NetStatusRequestPacket {
+    fn packed_len(&self) -> usize {
+        PacketsMinSize::NetStatusPacket as usize
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u16::<BigEndian>(SoeOpcode::NetStatusRequest as u16)?;
+        wtr.write_u16::<BigEndian>(self.client_tick_count)?;
+        wtr.write_u32::<BigEndian>(self.last_client_update)?;
+        wtr.write_u32::<BigEndian>(self.average_update)?;
+        wtr.write_u32::<BigEndian>(self.shortest_update)?;
+        wtr.write_u32::<BigEndian>(self.longest_update)?;
+        wtr.write_u32::<BigEndian>(self.last_server_update)?;
+        wtr.write_u64::<BigEndian>(self.packets_sent)?;
+        wtr.write_u64::<BigEndian>(self.packets_received)?;
+        wtr.write_u16::<BigEndian>(self.unknown_field)
+    }
+}
+
+impl PackInto for NetStatusReplyPacket {
+    fn packed_len(&self) -> usize {
+        PacketsMinSize::NetStatusPacket as usize
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_u16::<BigEndian>(SoeOpcode::NetStatusReply as u16)?;
+        wtr.write_u16::<BigEndian>(self.client_tick_count)?;
+        wtr.write_u32::<BigEndian>(self.server_tick_count)?;
+        wtr.write_u64::<BigEndian>(self.client_packet_sent)?;
+        wtr.write_u64::<BigEndian>(self.client_packet_received)?;
+        wtr.write_u64::<BigEndian>(self.server_packet_sent)?;
+        wtr.write_u64::<BigEndian>(self.server_packet_received)?;
+        wtr.write_u16::<BigEndian>(self.unknown_field)
+    }
+}
+
+fn nested_sub_packets_len(sub_packets: &[SoePacket]) -> usize {
+    2 + sub_packets
+        .iter()
+        .map(|sub_packet| {
+            let length = sub_packet.packed_len();
+            data_length_size(length) + length
+        })
+        .sum::<usize>()
+}
+
+fn write_nested_sub_packets<W: Write>(
+    wtr: &mut W,
+    opcode: u16,
+    sub_packets: &[SoePacket],
+) -> std::io::Result<()> {
+    wtr.write_u16::<BigEndian>(opcode)?;
+    for sub_packet in sub_packets {
+        write_data_length(wtr, sub_packet.packed_len());
+        sub_packet.write_to(wtr)?;
+    }
+    Ok(())
+}
+
+impl PackInto for SoePacket {
+    fn packed_len(&self) -> usize {
+        match self {
+            SoePacket::SessionRequest(packet) => packet.packed_len(),
+            SoePacket::SessionReply(packet) => packet.packed_len(),
+            SoePacket::Multi(sub_packets) | SoePacket::Group(sub_packets) => {
+                nested_sub_packets_len(sub_packets)
+            }
+            SoePacket::Disconnect(Some(packet)) => packet.packed_len(),
+            SoePacket::Disconnect(None) | SoePacket::Ping => 2,
+            SoePacket::NetStatusRequest(packet) => packet.packed_len(),
+            SoePacket::NetStatusReply(packet) => packet.packed_len(),
+            SoePacket::Data(packet)
+            | SoePacket::DataFragment(packet)
+            | SoePacket::Ordered(packet) => 4 + packet.data.len(),
+            SoePacket::OutOfOrder(_) | SoePacket::Ack(_) => 4,
+            SoePacket::FatalError(packet) => packet.packed_len(),
+            SoePacket::Unknown(raw) => raw.len(),
+        }
+    }
+
+    fn write_to<W: Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        match self {
+            SoePacket::SessionRequest(packet) => packet.write_to(wtr),
+            SoePacket::SessionReply(packet) => packet.write_to(wtr),
+            SoePacket::Multi(sub_packets) => {
+                write_nested_sub_packets(wtr, SoeOpcode::MultiPacket as u16, sub_packets)
+            }
+            SoePacket::Group(sub_packets) => {
+                write_nested_sub_packets(wtr, SoeOpcode::Group as u16, sub_packets)
+            }
+            SoePacket::Disconnect(Some(packet)) => packet.write_to(wtr),
+            SoePacket::Disconnect(None) => wtr.write_u16::<BigEndian>(SoeOpcode::Disconnect as u16),
+            SoePacket::Ping => wtr.write_u16::<BigEndian>(SoeOpcode::Ping as u16),
+            SoePacket::NetStatusRequest(packet) => packet.write_to(wtr),
+            SoePacket::NetStatusReply(packet) => packet.write_to(wtr),
+            SoePacket::Data(packet) => write_data_packet(
+                wtr,
+                SoeOpcode::Data.with_channel(packet.channel)?,
+                packet.sequence,
+                &packet.data,
+            ),
+            SoePacket::DataFragment(packet) => write_data_packet(
+                wtr,
+                SoeOpcode::DataFragment.with_channel(packet.channel)?,
+                packet.sequence,
+                &packet.data,
+            ),
+            SoePacket::OutOfOrder(packet) => write_ack_packet(
+                wtr,
+                SoeOpcode::OutOfOrder.with_channel(packet.channel)?,
+                packet.sequence,
+            ),
+            SoePacket::Ack(packet) => write_ack_packet(
+                wtr,
+                SoeOpcode::Ack.with_channel(packet.channel)?,
+                packet.sequence,
+            ),
+            SoePacket::Ordered(packet) => write_data_packet(
+                wtr,
+                SoeOpcode::Ordered as u16,
+                packet.sequence,
+                &packet.data,
+            ),
+            SoePacket::FatalError(packet) => packet.write_to(wtr),
+            SoePacket::Unknown(raw) => wtr.write_all(raw),
+        }
+    }
+}
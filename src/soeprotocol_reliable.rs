@@ -0,0 +1,958 @@
+use super::protocol_errors::ProtocolError;
+use super::rc4::RC4;
+use super::soeprotocol::{check_channel, Soeprotocol, RELIABLE_CHANNEL_COUNT};
+use super::soeprotocol_batcher::MultiPacketBatcher;
+use super::soeprotocol_fragments::FragmentAssembler;
+use super::soeprotocol_packets_structs::{AckPacket, DataPacket};
+use super::soeprotocol_sequence::{wire_sequence, SequenceTracker};
+use std::collections::{HashMap, VecDeque};
+
+// rfc 6298 defaults, in milliseconds
+const INITIAL_RTO: u64 = 1000;
+const MIN_RTO: u64 = 100;
+const MAX_RTO: u64 = 10000;
+const RTT_ALPHA: f64 = 0.125;
+const RTT_BETA: f64 = 0.25;
+// how far ahead of the expected sequence packets are buffered
+const RECEIVE_WINDOW: u64 = 1024;
+
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<f64>,
+    rttvar: f64,
+    rto: u64,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: 0.0,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn add_sample(&mut self, rtt: u64) {
+        let rtt = rtt as f64;
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2.0;
+            }
+            Some(srtt) => {
+                self.rttvar = (1.0 - RTT_BETA) * self.rttvar + RTT_BETA * (srtt - rtt).abs();
+                self.srtt = Some((1.0 - RTT_ALPHA) * srtt + RTT_ALPHA * rtt);
+            }
+        }
+        let rto = self.srtt.unwrap_or_default() + (4.0 * self.rttvar).max(1.0);
+        self.rto = (rto.ceil() as u64).clamp(MIN_RTO, MAX_RTO);
+    }
+    pub fn get_srtt(&self) -> Option<u64> {
+        self.srtt.map(|srtt| srtt.round() as u64)
+    }
+    pub fn get_rttvar(&self) -> u64 {
+        self.rttvar.round() as u64
+    }
+    pub fn get_rto(&self) -> u64 {
+        self.rto
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingPacket {
+    sequence: u64,
+    packet: Vec<u8>,
+    first_sent_at: u64,
+    sent_at: u64,
+    retransmits: u32,
+}
+
+// outbound reliable window, packets stay here until the peer acknowledges them
+#[derive(Debug, Default)]
+pub struct ReliableSender {
+    channel: u8,
+    // extended sequences, only the low 16 bits go on the wire
+    next_sequence: SequenceTracker,
+    pending: VecDeque<PendingPacket>,
+    rtt: RttEstimator,
+}
+
+impl ReliableSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_channel(channel: u8) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            channel: check_channel(channel)?,
+            ..Self::default()
+        })
+    }
+
+    pub fn get_channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn get_next_sequence(&self) -> u16 {
+        self.next_sequence.get_wire_next()
+    }
+
+    pub fn get_extended_next_sequence(&self) -> u64 {
+        self.next_sequence.get_next()
+    }
+
+    // record an already packed reliable packet using the next sequence
+    pub fn track(&mut self, packet: Vec<u8>, now: u64) -> u16 {
+        let sequence = self.next_sequence.advance();
+        self.pending.push_back(PendingPacket {
+            sequence,
+            packet,
+            first_sent_at: now,
+            sent_at: now,
+            retransmits: 0,
+        });
+        wire_sequence(sequence)
+    }
+
+    pub fn send_data(
+        &mut self,
+        soeprotocol: &mut Soeprotocol,
+        data: &[u8],
+        udp_length: u32,
+        crc_length: u8,
+        now: u64,
+    ) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        let packets = soeprotocol.pack_reliable_data(
+            data,
+            self.channel,
+            self.next_sequence.get_wire_next(),
+            udp_length,
+            crc_length,
+        )?;
+        for packet in &packets {
+            self.track(packet.clone(), now);
+        }
+        Ok(packets)
+    }
+
+    // acks are cumulative, everything up to the sequence is acknowledged
+    pub fn on_ack(&mut self, sequence: u16, now: u64) {
+        let Some(oldest) = self.pending.front().map(|pending| pending.sequence) else {
+            return;
+        };
+        let acked = self.next_sequence.extend(sequence);
+        // an ack behind the window is stale, one past what we sent is bogus
+        if acked < oldest || acked >= self.next_sequence.get_next() {
+            return;
+        }
+        while let Some(pending) = self.pending.front() {
+            if pending.sequence > acked {
+                break;
+            }
+            if let Some(pending) = self.pending.pop_front() {
+                if pending.sequence == acked {
+                    self.sample_rtt(&pending, now);
+                }
+            }
+        }
+    }
+
+    pub fn on_out_of_order(&mut self, sequence: u16, now: u64) {
+        if let Some(index) = self.position(sequence) {
+            if let Some(acked) = self.pending.remove(index) {
+                self.sample_rtt(&acked, now);
+            }
+        }
+    }
+
+    pub fn get_packets_to_resend(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let rto = self.rtt.get_rto();
+        let mut packets = vec![];
+        for pending in self.pending.iter_mut() {
+            let timeout = rto
+                .saturating_mul(1 << pending.retransmits.min(16))
+                .min(MAX_RTO);
+            if now.saturating_sub(pending.sent_at) >= timeout {
+                pending.sent_at = now;
+                pending.retransmits += 1;
+                packets.push(pending.packet.clone());
+            }
+        }
+        packets
+    }
+
+    pub fn get_pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    // when the oldest packet still waiting for an ack was first sent
+    pub fn get_oldest_unacked_since(&self) -> Option<u64> {
+        self.pending.front().map(|pending| pending.first_sent_at)
+    }
+
+    pub fn is_pending(&self, sequence: u16) -> bool {
+        self.position(sequence).is_some()
+    }
+
+    pub fn get_rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    fn position(&self, sequence: u16) -> Option<usize> {
+        let sequence = self.next_sequence.extend(sequence);
+        self.pending
+            .iter()
+            .position(|pending| pending.sequence == sequence)
+    }
+
+    fn sample_rtt(&mut self, acked: &PendingPacket, now: u64) {
+        // karn's algorithm: retransmitted packets give ambiguous samples
+        if acked.retransmits == 0 {
+            self.rtt.add_sample(now.saturating_sub(acked.sent_at));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AckPolicy {
+    // longest time an ack is held back, in milliseconds
+    pub delay: u64,
+    // ack right away once that many packets wait for an ack
+    pub max_pending: u32,
+}
+
+impl Default for AckPolicy {
+    fn default() -> Self {
+        Self::immediate()
+    }
+}
+
+impl AckPolicy {
+    pub fn immediate() -> Self {
+        Self {
+            delay: 0,
+            max_pending: 1,
+        }
+    }
+    pub fn delayed(delay: u64, max_pending: u32) -> Self {
+        Self { delay, max_pending }
+    }
+}
+
+#[derive(Debug)]
+struct PendingAck {
+    since: u64,
+    count: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReceivedData {
+    // complete reliable payloads in sequence order
+    pub payloads: Vec<Vec<u8>>,
+    // packed ack / out of order packets to send back
+    pub replies: Vec<Vec<u8>>,
+    // fragments the assembler refused, the payload they belonged to is dropped
+    pub errors: Vec<ProtocolError>,
+}
+
+#[derive(Debug)]
+struct BufferedPacket {
+    data: Vec<u8>,
+    fragment: bool,
+}
+
+// inbound reliable stream, reorders data packets and reassembles fragments
+#[derive(Debug, Default)]
+pub struct ReliableReceiver {
+    channel: u8,
+    next_sequence: SequenceTracker,
+    buffered: HashMap<u64, BufferedPacket>,
+    assembler: FragmentAssembler,
+    ack_policy: AckPolicy,
+    pending_ack: Option<PendingAck>,
+}
+
+impl ReliableReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ack_policy(ack_policy: AckPolicy) -> Self {
+        Self {
+            ack_policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_channel(channel: u8) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            channel: check_channel(channel)?,
+            ..Self::default()
+        })
+    }
+
+    pub fn get_channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn get_ack_policy(&self) -> AckPolicy {
+        self.ack_policy
+    }
+
+    pub fn set_ack_policy(&mut self, ack_policy: AckPolicy) {
+        self.ack_policy = ack_policy;
+    }
+
+    pub fn get_next_sequence(&self) -> u16 {
+        self.next_sequence.get_wire_next()
+    }
+
+    pub fn get_extended_next_sequence(&self) -> u64 {
+        self.next_sequence.get_next()
+    }
+
+    pub fn get_buffered_count(&self) -> usize {
+        self.buffered.len()
+    }
+
+    pub fn on_data(
+        &mut self,
+        soeprotocol: &mut Soeprotocol,
+        packet: DataPacket,
+        fragment: bool,
+        now: u64,
+    ) -> Result<ReceivedData, ProtocolError> {
+        let mut received = ReceivedData::default();
+        let next_sequence = self.next_sequence.get_next();
+        let sequence = self.next_sequence.extend(packet.get_sequence());
+        if sequence < next_sequence {
+            // duplicate of an already delivered packet, the peer probably missed our ack
+            self.pending_ack = None;
+            received.replies.push(self.pack_ack(soeprotocol));
+            return Ok(received);
+        }
+        let distance = sequence - next_sequence;
+        if distance >= RECEIVE_WINDOW {
+            return Err(ProtocolError::Sequence {
+                expected: wire_sequence(next_sequence),
+                given: packet.get_sequence(),
+            });
+        }
+        if distance > 0 {
+            self.buffered.entry(sequence).or_insert(BufferedPacket {
+                data: packet.into_data(),
+                fragment,
+            });
+            let mut out_of_order = AckPacket::new(wire_sequence(sequence));
+            out_of_order.set_channel(self.channel)?;
+            received
+                .replies
+                .push(soeprotocol.pack_out_of_order_object(out_of_order));
+            return Ok(received);
+        }
+        let mut delivered = 0;
+        self.deliver(packet.into_data(), fragment, &mut received);
+        delivered += 1;
+        while let Some(buffered) = self.buffered.remove(&self.next_sequence.get_next()) {
+            self.deliver(buffered.data, buffered.fragment, &mut received);
+            delivered += 1;
+        }
+        // only the highest contiguous sequence is acknowledged
+        let pending_ack = self.pending_ack.get_or_insert(PendingAck {
+            since: now,
+            count: 0,
+        });
+        pending_ack.count += delivered;
+        if pending_ack.count >= self.ack_policy.max_pending || self.ack_policy.delay == 0 {
+            if let Some(ack) = self.take_ack(soeprotocol) {
+                received.replies.push(ack);
+            }
+        }
+        Ok(received)
+    }
+
+    pub fn has_pending_ack(&self) -> bool {
+        self.pending_ack.is_some()
+    }
+
+    // time at which the pending ack must be sent
+    pub fn get_ack_deadline(&self) -> Option<u64> {
+        self.pending_ack
+            .as_ref()
+            .map(|pending_ack| pending_ack.since.saturating_add(self.ack_policy.delay))
+    }
+
+    // returns the pending ack once its delay expired
+    pub fn poll_ack(&mut self, soeprotocol: &mut Soeprotocol, now: u64) -> Option<Vec<u8>> {
+        if now >= self.get_ack_deadline()? {
+            return self.take_ack(soeprotocol);
+        }
+        None
+    }
+
+    // returns the pending ack right away
+    pub fn take_ack(&mut self, soeprotocol: &mut Soeprotocol) -> Option<Vec<u8>> {
+        self.pending_ack.take()?;
+        Some(self.pack_ack(soeprotocol))
+    }
+
+    // queue the pending ack on the batcher so it leaves with the next frame
+    // returns the frames the batcher sent to make room for it
+    pub fn push_ack(
+        &mut self,
+        soeprotocol: &mut Soeprotocol,
+        batcher: &mut MultiPacketBatcher,
+        now: u64,
+    ) -> Vec<Vec<u8>> {
+        match self.take_ack(soeprotocol) {
+            Some(ack) => batcher.push(soeprotocol, ack, now),
+            None => vec![],
+        }
+    }
+
+    fn pack_ack(&self, soeprotocol: &mut Soeprotocol) -> Vec<u8> {
+        let mut ack = AckPacket::new(self.next_sequence.get_wire_next().wrapping_sub(1));
+        // the channel was checked by with_channel
+        ack.set_channel(self.channel).unwrap_or_default();
+        soeprotocol.pack_ack_object(ack)
+    }
+
+    // the sequence is consumed even when the fragment is refused, so it still gets acked
+    fn deliver(&mut self, data: Vec<u8>, fragment: bool, received: &mut ReceivedData) {
+        let sequence = wire_sequence(self.next_sequence.advance());
+        if !fragment {
+            received.payloads.push(data);
+            return;
+        }
+        // the assembler resets itself on error
+        match self.assembler.add_fragment(&data, sequence) {
+            Ok(Some(payload)) => received.payloads.push(payload),
+            Ok(None) => {}
+            Err(error) => received.errors.push(error),
+        }
+    }
+}
+
+// rc4 streams applied to whole reliable payloads, both start from the session key
+pub struct ReliableCipher {
+    inbound: RC4,
+    outbound: RC4,
+}
+
+impl ReliableCipher {
+    pub fn new(key: &[u8]) -> Result<Self, ProtocolError> {
+        if key.is_empty() {
+            return Err(ProtocolError::Handshake("empty encryption key".to_owned()));
+        }
+        Ok(Self {
+            inbound: RC4::initialize(key.to_vec()),
+            outbound: RC4::initialize(key.to_vec()),
+        })
+    }
+
+    pub fn encrypt(&mut self, payload: Vec<u8>) -> Vec<u8> {
+        let encrypted = self.outbound.encrypt(payload);
+        // h1emu reads a leading zero as a marker, so it gets escaped with another one
+        if encrypted.first() == Some(&0) {
+            let mut escaped = Vec::with_capacity(encrypted.len() + 1);
+            escaped.push(0);
+            escaped.extend_from_slice(&encrypted);
+            return escaped;
+        }
+        encrypted
+    }
+
+    pub fn decrypt(&mut self, mut payload: Vec<u8>) -> Vec<u8> {
+        if payload.first() == Some(&0) {
+            payload.remove(0);
+        }
+        self.inbound.decrypt(payload)
+    }
+}
+
+// one sender and one receiver per reliable channel, each with its own sequence space
+#[derive(Debug)]
+pub struct ReliableChannels {
+    senders: [ReliableSender; RELIABLE_CHANNEL_COUNT as usize],
+    receivers: [ReliableReceiver; RELIABLE_CHANNEL_COUNT as usize],
+}
+
+impl Default for ReliableChannels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReliableChannels {
+    pub fn new() -> Self {
+        Self {
+            senders: std::array::from_fn(|channel| ReliableSender {
+                channel: channel as u8,
+                ..ReliableSender::default()
+            }),
+            receivers: std::array::from_fn(|channel| ReliableReceiver {
+                channel: channel as u8,
+                ..ReliableReceiver::default()
+            }),
+        }
+    }
+
+    pub fn get_sender(&mut self, channel: u8) -> Option<&mut ReliableSender> {
+        self.senders.get_mut(channel as usize)
+    }
+
+    pub fn get_receiver(&mut self, channel: u8) -> Option<&mut ReliableReceiver> {
+        self.receivers.get_mut(channel as usize)
+    }
+
+    pub fn senders(&self) -> impl Iterator<Item = &ReliableSender> {
+        self.senders.iter()
+    }
+
+    // flush the batcher with the pending acks folded in, returns the finalized frames
+    // acks that aren't due yet only ride along when something else is queued
+    pub fn flush_with_acks(
+        &mut self,
+        soeprotocol: &mut Soeprotocol,
+        batcher: &mut MultiPacketBatcher,
+        now: u64,
+    ) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        for receiver in self.receivers.iter_mut() {
+            let due = receiver
+                .get_ack_deadline()
+                .is_some_and(|deadline| now >= deadline);
+            if due || batcher.get_queued_count() > 0 {
+                frames.extend(receiver.push_ack(soeprotocol, batcher, now));
+            }
+        }
+        frames.extend(batcher.flush(soeprotocol));
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::protocol_errors::ProtocolError;
+    use super::super::soeprotocol::Soeprotocol;
+    use super::super::soeprotocol_batcher::MultiPacketBatcher;
+    use super::super::soeprotocol_packets_structs::DataPacket;
+    use super::super::soeprotocol_sequence::SequenceTracker;
+
+    #[test]
+    fn reliable_sender_ack_test() {
+        let mut sender = super::ReliableSender::new();
+        for i in 0..5 {
+            sender.track(vec![0, 9, 0, i], 0);
+        }
+        sender.on_ack(2, 50);
+        assert_eq!(sender.get_pending_count(), 2);
+        assert!(!sender.is_pending(2));
+        assert!(sender.is_pending(3));
+        assert_eq!(sender.get_rtt().get_srtt(), Some(50))
+    }
+
+    #[test]
+    fn reliable_sender_future_ack_test() {
+        let mut sender = super::ReliableSender::new();
+        for i in 0..5 {
+            sender.track(vec![0, 9, 0, i], 0);
+        }
+        sender.on_ack(1000, 50);
+        sender.on_ack(5, 50);
+        assert_eq!(sender.get_pending_count(), 5);
+        assert_eq!(sender.get_rtt().get_srtt(), None)
+    }
+
+    #[test]
+    fn reliable_sender_out_of_order_test() {
+        let mut sender = super::ReliableSender::new();
+        for i in 0..3 {
+            sender.track(vec![0, 9, 0, i], 0);
+        }
+        sender.on_out_of_order(1, 10);
+        assert!(sender.is_pending(0));
+        assert!(!sender.is_pending(1));
+        assert!(sender.is_pending(2));
+        sender.on_ack(1, 20);
+        assert_eq!(sender.get_pending_count(), 1);
+        assert!(sender.is_pending(2))
+    }
+
+    #[test]
+    fn reliable_sender_resend_test() {
+        let mut sender = super::ReliableSender::new();
+        sender.track(vec![0, 9, 0, 0], 0);
+        assert!(sender.get_packets_to_resend(999).is_empty());
+        assert_eq!(sender.get_packets_to_resend(1000), vec![vec![0, 9, 0, 0]]);
+        // the timeout doubles after each retransmission
+        assert!(sender.get_packets_to_resend(2999).is_empty());
+        assert_eq!(sender.get_packets_to_resend(3000).len(), 1);
+        // retransmitted packets are not used as rtt samples
+        sender.on_ack(0, 3100);
+        assert_eq!(sender.get_rtt().get_srtt(), None);
+        assert_eq!(sender.get_pending_count(), 0)
+    }
+
+    #[test]
+    fn reliable_sender_rto_test() {
+        let mut sender = super::ReliableSender::new();
+        for i in 0..10 {
+            sender.track(vec![0, 9, 0, i], i as u64 * 100);
+            sender.on_ack(i as u16, i as u64 * 100 + 40);
+        }
+        assert_eq!(sender.get_rtt().get_srtt(), Some(40));
+        assert_eq!(sender.get_rtt().get_rto(), super::MIN_RTO);
+        sender.track(vec![0, 9, 0, 10], 2000);
+        assert_eq!(sender.get_packets_to_resend(2000 + super::MIN_RTO).len(), 1)
+    }
+
+    #[test]
+    fn reliable_sender_wrap_test() {
+        let mut sender = super::ReliableSender::new();
+        sender.next_sequence = SequenceTracker::starting_at(65534);
+        for _ in 0..4 {
+            sender.track(vec![], 0);
+        }
+        sender.on_ack(0, 10);
+        assert_eq!(sender.get_pending_count(), 1);
+        assert!(sender.is_pending(1))
+    }
+
+    #[test]
+    fn reliable_sender_send_data_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut sender = super::ReliableSender::new();
+        let data: Vec<u8> = (0..20).collect();
+        let packets = sender
+            .send_data(&mut soeprotocol_class, &data, 16, 0, 0)
+            .unwrap();
+        assert_eq!(packets.len(), sender.get_pending_count());
+        assert_eq!(sender.get_next_sequence(), packets.len() as u16)
+    }
+
+    #[test]
+    fn reliable_receiver_in_order_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver = super::ReliableReceiver::new();
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 0),
+                false,
+                0,
+            )
+            .unwrap();
+        assert_eq!(received.payloads, vec![vec![1]]);
+        assert_eq!(received.replies, vec![vec![0, 21, 0, 0]])
+    }
+
+    #[test]
+    fn reliable_receiver_out_of_order_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver = super::ReliableReceiver::new();
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![2], 1),
+                false,
+                0,
+            )
+            .unwrap();
+        assert!(received.payloads.is_empty());
+        assert_eq!(received.replies, vec![vec![0, 17, 0, 1]]);
+        assert_eq!(receiver.get_buffered_count(), 1);
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 0),
+                false,
+                0,
+            )
+            .unwrap();
+        assert_eq!(received.payloads, vec![vec![1], vec![2]]);
+        assert_eq!(received.replies, vec![vec![0, 21, 0, 1]]);
+        assert_eq!(receiver.get_buffered_count(), 0)
+    }
+
+    #[test]
+    fn reliable_receiver_bad_fragment_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver = super::ReliableReceiver::new();
+        // too short to hold the total length
+        receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![0, 1], 1),
+                true,
+                0,
+            )
+            .unwrap();
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 0),
+                false,
+                0,
+            )
+            .unwrap();
+        assert_eq!(received.payloads, vec![vec![1]]);
+        assert_eq!(received.replies, vec![vec![0, 21, 0, 1]]);
+        assert_eq!(received.errors, vec![ProtocolError::Size { size: 2 }]);
+        assert_eq!(receiver.get_next_sequence(), 2);
+        // the assembler starts over with the next payload
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![0, 0, 0, 1, 3], 2),
+                true,
+                0,
+            )
+            .unwrap();
+        assert_eq!(received.payloads, vec![vec![3]])
+    }
+
+    #[test]
+    fn reliable_receiver_duplicate_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver = super::ReliableReceiver::new();
+        for _ in 0..2 {
+            let received = receiver
+                .on_data(
+                    &mut soeprotocol_class,
+                    DataPacket::new(vec![1], 0),
+                    false,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(received.replies, vec![vec![0, 21, 0, 0]]);
+        }
+        assert_eq!(receiver.get_next_sequence(), 1)
+    }
+
+    #[test]
+    fn reliable_receiver_wrap_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver = super::ReliableReceiver::new();
+        receiver.next_sequence = SequenceTracker::starting_at(65535);
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![2], 0),
+                false,
+                0,
+            )
+            .unwrap();
+        assert_eq!(received.replies, vec![vec![0, 17, 0, 0]]);
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 65535),
+                false,
+                0,
+            )
+            .unwrap();
+        assert_eq!(received.payloads, vec![vec![1], vec![2]]);
+        assert_eq!(received.replies, vec![vec![0, 21, 0, 0]]);
+        assert_eq!(receiver.get_extended_next_sequence(), 65537);
+        // 65535 is now behind the window
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 65535),
+                false,
+                0,
+            )
+            .unwrap();
+        assert!(received.payloads.is_empty())
+    }
+
+    #[test]
+    fn reliable_receiver_window_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver = super::ReliableReceiver::new();
+        assert_eq!(
+            receiver.on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 1024),
+                false,
+                0,
+            ),
+            Err(ProtocolError::Sequence {
+                expected: 0,
+                given: 1024,
+            })
+        );
+        assert_eq!(receiver.get_buffered_count(), 0);
+        assert!(!receiver.has_pending_ack())
+    }
+
+    #[test]
+    fn reliable_receiver_fragments_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver = super::ReliableReceiver::new();
+        let received = receiver
+            .on_data(&mut soeprotocol_class, DataPacket::new(vec![3], 1), true, 0)
+            .unwrap();
+        assert!(received.payloads.is_empty());
+        let received = receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![0, 0, 0, 2, 4], 0),
+                true,
+                0,
+            )
+            .unwrap();
+        assert_eq!(received.payloads, vec![vec![4, 3]])
+    }
+
+    #[test]
+    fn reliable_receiver_delayed_ack_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver =
+            super::ReliableReceiver::with_ack_policy(super::AckPolicy::delayed(50, 3));
+        for sequence in 0..2 {
+            let received = receiver
+                .on_data(
+                    &mut soeprotocol_class,
+                    DataPacket::new(vec![1], sequence),
+                    false,
+                    10,
+                )
+                .unwrap();
+            assert!(received.replies.is_empty());
+        }
+        assert_eq!(receiver.get_ack_deadline(), Some(60));
+        assert_eq!(receiver.poll_ack(&mut soeprotocol_class, 59), None);
+        assert_eq!(
+            receiver.poll_ack(&mut soeprotocol_class, 60),
+            Some(vec![0, 21, 0, 1])
+        );
+        assert!(!receiver.has_pending_ack())
+    }
+
+    #[test]
+    fn reliable_receiver_max_pending_ack_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut receiver =
+            super::ReliableReceiver::with_ack_policy(super::AckPolicy::delayed(50, 3));
+        let mut replies = vec![];
+        for sequence in 0..3 {
+            let received = receiver
+                .on_data(
+                    &mut soeprotocol_class,
+                    DataPacket::new(vec![1], sequence),
+                    false,
+                    0,
+                )
+                .unwrap();
+            replies.extend(received.replies);
+        }
+        // a single ack for the highest contiguous sequence
+        assert_eq!(replies, vec![vec![0, 21, 0, 2]])
+    }
+
+    #[test]
+    fn reliable_receiver_multi_with_ack_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut channels = super::ReliableChannels::new();
+        let mut batcher = MultiPacketBatcher::new(512, 0);
+        let receiver = channels.get_receiver(0).unwrap();
+        receiver.set_ack_policy(super::AckPolicy::delayed(50, 3));
+        receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 0),
+                false,
+                0,
+            )
+            .unwrap();
+        // not due and nothing to ride along
+        assert!(channels
+            .flush_with_acks(&mut soeprotocol_class, &mut batcher, 10)
+            .is_empty());
+        batcher.push(&soeprotocol_class, vec![0, 9, 0, 0, 7], 10);
+        assert_eq!(
+            channels.flush_with_acks(&mut soeprotocol_class, &mut batcher, 10),
+            vec![vec![0, 3, 5, 0, 9, 0, 0, 7, 4, 0, 21, 0, 0]]
+        );
+        assert!(!channels.get_receiver(0).unwrap().has_pending_ack())
+    }
+
+    #[test]
+    fn reliable_channels_flush_udp_length_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut channels = super::ReliableChannels::new();
+        // room for the multi header, a 9 bytes data packet and nothing else
+        let mut batcher = MultiPacketBatcher::new(12, 0);
+        let receiver = channels.get_receiver(1).unwrap();
+        receiver.set_ack_policy(super::AckPolicy::delayed(50, 3));
+        receiver
+            .on_data(
+                &mut soeprotocol_class,
+                DataPacket::new(vec![1], 0),
+                false,
+                0,
+            )
+            .unwrap();
+        let data = vec![0, 9, 0, 0, 1, 2, 3, 4, 5];
+        batcher.push(&soeprotocol_class, data.clone(), 0);
+        let frames = channels.flush_with_acks(&mut soeprotocol_class, &mut batcher, 0);
+        assert_eq!(frames, vec![data, vec![0, 22, 0, 0]]);
+        assert!(frames.iter().all(|frame| frame.len() <= 12))
+    }
+
+    #[test]
+    fn reliable_channels_test() {
+        let mut soeprotocol_class = Soeprotocol::initialize(false, 0);
+        let mut channels = super::ReliableChannels::new();
+        let packets = channels
+            .get_sender(2)
+            .unwrap()
+            .send_data(&mut soeprotocol_class, &[1, 2], 512, 0, 0)
+            .unwrap();
+        assert_eq!(packets, vec![vec![0, 11, 0, 0, 1, 2]]);
+        assert_eq!(channels.get_sender(0).unwrap().get_next_sequence(), 0);
+        let mut packet = DataPacket::new(vec![1, 2], 0);
+        packet.set_channel(2).unwrap();
+        let received = channels
+            .get_receiver(2)
+            .unwrap()
+            .on_data(&mut soeprotocol_class, packet, false, 0)
+            .unwrap();
+        assert_eq!(received.replies, vec![vec![0, 23, 0, 0]]);
+        assert_eq!(channels.get_receiver(0).unwrap().get_next_sequence(), 0);
+        assert!(channels.get_receiver(4).is_none());
+        assert!(matches!(
+            super::ReliableReceiver::with_channel(4),
+            Err(ProtocolError::Channel { channel: 4 })
+        ))
+    }
+
+    #[test]
+    fn reliable_cipher_test() {
+        let key = [23, 189, 8, 107, 27, 148, 240, 47];
+        let mut client = super::ReliableCipher::new(&key).unwrap();
+        let mut server = super::ReliableCipher::new(&key).unwrap();
+        for payload in [vec![0, 1, 2, 3], vec![4; 20], vec![]] {
+            let encrypted = client.encrypt(payload.clone());
+            assert_eq!(server.decrypt(encrypted), payload);
+        }
+        assert!(super::ReliableCipher::new(&[]).is_err())
+    }
+
+    #[test]
+    fn reliable_cipher_zero_prefix_test() {
+        let key = [1, 2, 3, 4];
+        let mut rc4 = super::RC4::initialize(key.to_vec());
+        let first_byte = rc4.encrypt(vec![0])[0];
+        // picks a payload that encrypts to a leading zero
+        let mut client = super::ReliableCipher::new(&key).unwrap();
+        let encrypted = client.encrypt(vec![first_byte, 9]);
+        assert_eq!(encrypted[..2], [0, 0]);
+        let mut server = super::ReliableCipher::new(&key).unwrap();
+        assert_eq!(server.decrypt(encrypted), vec![first_byte, 9])
+    }
+}
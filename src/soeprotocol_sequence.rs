@@ -0,0 +1,95 @@
+// serial number arithmetic over the u16 sequence space (rfc 1982)
+const SEQUENCE_SPACE: u64 = 0x10000;
+
+// signed distance from a to b, positive when b comes after a
+pub fn sequence_distance(a: u16, b: u16) -> i32 {
+    b.wrapping_sub(a) as i16 as i32
+}
+
+// the extended value closest to reference with the same low 16 bits as sequence
+pub fn extend_sequence(sequence: u16, reference: u64) -> u64 {
+    let distance = sequence_distance(reference as u16, sequence) as i64;
+    let extended = reference as i64 + distance;
+    if extended < 0 {
+        // nothing comes before 0, the sequence is ahead after all
+        (extended + SEQUENCE_SPACE as i64) as u64
+    } else {
+        extended as u64
+    }
+}
+
+pub fn wire_sequence(extended: u64) -> u16 {
+    extended as u16
+}
+
+// the next sequence of a reliable stream, wire sequences are extended around it
+#[derive(Debug, Default, Clone)]
+pub struct SequenceTracker {
+    next: u64,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn starting_at(next: u64) -> Self {
+        Self { next }
+    }
+
+    pub fn extend(&self, sequence: u16) -> u64 {
+        extend_sequence(sequence, self.next)
+    }
+
+    // consume the next sequence
+    pub fn advance(&mut self) -> u64 {
+        let sequence = self.next;
+        self.next += 1;
+        sequence
+    }
+
+    pub fn get_next(&self) -> u64 {
+        self.next
+    }
+
+    pub fn get_wire_next(&self) -> u16 {
+        wire_sequence(self.next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_distance_test() {
+        assert_eq!(sequence_distance(0, 1), 1);
+        assert_eq!(sequence_distance(5, 5), 0);
+        assert_eq!(sequence_distance(65534, 1), 3);
+        assert_eq!(sequence_distance(1, 65534), -3)
+    }
+
+    #[test]
+    fn extend_sequence_test() {
+        assert_eq!(extend_sequence(0, 65535), 65536);
+        assert_eq!(extend_sequence(65535, 65536), 65535);
+        assert_eq!(
+            extend_sequence(10, 3 * SEQUENCE_SPACE + 65530),
+            4 * SEQUENCE_SPACE + 10
+        );
+        // no negative values at the start of the stream
+        assert_eq!(extend_sequence(65535, 0), 65535)
+    }
+
+    #[test]
+    fn sequence_tracker_test() {
+        let mut tracker = SequenceTracker::starting_at(65534);
+        assert_eq!(tracker.advance(), 65534);
+        assert_eq!(tracker.advance(), 65535);
+        assert_eq!(tracker.get_next(), 65536);
+        assert_eq!(tracker.get_wire_next(), 0);
+        assert_eq!(tracker.extend(65535), 65535);
+        assert_eq!(tracker.extend(2), 65538);
+        assert_eq!(SequenceTracker::new().extend(65535), 65535)
+    }
+}
@@ -0,0 +1,761 @@
+use super::packing::PackInto;
+use super::protocol_errors::ProtocolError;
+use super::soeprotocol::{DisconnectReason, EncryptMethod, Soeprotocol};
+use super::soeprotocol_net_status::{ClockSync, NetStatusStats};
+use super::soeprotocol_packets_structs::{
+    DataPacket, NetStatusReplyPacket, NetStatusRequestPacket, SessionReplyPacket,
+    SessionRequestPacket, SoePacket,
+};
+use super::soeprotocol_reliable::{ReceivedData, ReliableCipher, ReliableReceiver, ReliableSender};
+use rand::random;
+
+pub use super::crc::DEFAULT_CRC_LENGTH;
+
+pub const PROTOCOL_VERSION: u32 = 3;
+// keepalive defaults, in milliseconds
+pub const DEFAULT_PING_INTERVAL: u64 = 5000;
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 30000;
+pub const DEFAULT_UNACKED_TIMEOUT: u64 = 60000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionRole {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Idle,
+    Requested,
+    Established,
+    Disconnecting,
+    Closed,
+}
+
+// a zero duration disables the matching check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    // clients ping the server when this much time passed since the last ping
+    pub ping_interval: u64,
+    // the session times out when nothing was received for this long
+    pub idle_timeout: u64,
+    // the session times out when reliable data stays unacknowledged for this long
+    pub unacked_timeout: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: DEFAULT_PING_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            unacked_timeout: DEFAULT_UNACKED_TIMEOUT,
+        }
+    }
+}
+
+// drives the session request / reply handshake and keeps the codec configured accordingly
+pub struct SoeSession {
+    role: SessionRole,
+    state: SessionState,
+    protocol: String,
+    session_id: u32,
+    crc_seed: u32,
+    crc_length: u8,
+    encrypt_method: u16,
+    udp_length: u32,
+    codec: Soeprotocol,
+    cipher: Option<ReliableCipher>,
+    keepalive: KeepaliveConfig,
+    last_received: Option<u64>,
+    last_ping: Option<u64>,
+    net_status: NetStatusStats,
+    // the last request sent, matched against the server reply
+    net_status_request: Option<NetStatusRequestPacket>,
+    clock_sync: ClockSync,
+}
+
+impl SoeSession {
+    fn new(role: SessionRole, protocol: &str, udp_length: u32, crc_length: u8) -> Self {
+        Self {
+            role,
+            state: SessionState::Idle,
+            protocol: protocol.to_owned(),
+            session_id: 0,
+            crc_seed: 0,
+            crc_length,
+            encrypt_method: EncryptMethod::EncryptMethodNone as u16,
+            udp_length,
+            // nothing is crc'd before the handshake is done
+            codec: Soeprotocol::initialize(false, 0),
+            cipher: None,
+            keepalive: KeepaliveConfig::default(),
+            last_received: None,
+            last_ping: None,
+            net_status: NetStatusStats::new(),
+            net_status_request: None,
+            clock_sync: ClockSync::new(),
+        }
+    }
+
+    pub fn client(protocol: &str, udp_length: u32) -> Self {
+        Self::new(SessionRole::Client, protocol, udp_length, 0)
+    }
+
+    pub fn server(protocol: &str, udp_length: u32, crc_length: u8) -> Self {
+        Self::new(SessionRole::Server, protocol, udp_length, crc_length)
+    }
+
+    // client side, generates a session id and packs the session request
+    pub fn connect(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        if self.role != SessionRole::Client || self.state != SessionState::Idle {
+            return Err(ProtocolError::Handshake(format!(
+                "cannot connect a {:?} session in {:?} state",
+                self.role, self.state
+            )));
+        }
+        self.session_id = random();
+        self.state = SessionState::Requested;
+        Ok(self
+            .codec
+            .pack_session_request_object(SessionRequestPacket::new(
+                self.session_id,
+                PROTOCOL_VERSION,
+                self.udp_length,
+                self.protocol.clone(),
+            )))
+    }
+
+    // validates the packet against the current state, returns a packet to send back if any
+    pub fn handle_packet(&mut self, packet: &SoePacket) -> Result<Option<Vec<u8>>, ProtocolError> {
+        match (self.role, self.state, packet) {
+            (SessionRole::Server, SessionState::Idle, SoePacket::SessionRequest(request)) => {
+                if request.get_protocol() != self.protocol {
+                    return Err(ProtocolError::Handshake(format!(
+                        "unsupported protocol {}",
+                        request.get_protocol()
+                    )));
+                }
+                self.session_id = request.session_id;
+                self.udp_length = self.udp_length.min(request.udp_length);
+                self.crc_seed = random();
+                self.configure_codec()?;
+                self.state = SessionState::Established;
+                Ok(Some(self.pack_session_reply()))
+            }
+            // our reply got lost, the client is asking again
+            (
+                SessionRole::Server,
+                SessionState::Established,
+                SoePacket::SessionRequest(request),
+            ) if request.session_id == self.session_id => Ok(Some(self.pack_session_reply())),
+            (SessionRole::Client, SessionState::Requested, SoePacket::SessionReply(reply)) => {
+                self.check_session_id(reply.session_id)?;
+                self.codec.set_crc_length(reply.crc_length)?;
+                self.codec.set_encrypt_method(reply.encrypt_method)?;
+                self.crc_seed = reply.crc_seed;
+                self.crc_length = reply.crc_length;
+                self.encrypt_method = reply.encrypt_method;
+                self.udp_length = reply.udp_length;
+                self.configure_codec()?;
+                self.state = SessionState::Established;
+                Ok(None)
+            }
+            (SessionRole::Client, SessionState::Established, SoePacket::SessionReply(reply))
+                if reply.session_id == self.session_id =>
+            {
+                Ok(None)
+            }
+            // servers answer the client heartbeat
+            (SessionRole::Server, SessionState::Established, SoePacket::Ping) => {
+                Ok(Some(self.pack_ping()))
+            }
+            (
+                _,
+                SessionState::Established | SessionState::Disconnecting,
+                SoePacket::Disconnect(disconnect),
+            ) => {
+                if let Some(disconnect) = disconnect {
+                    self.check_session_id(disconnect.session_id)?;
+                }
+                self.state = SessionState::Closed;
+                Ok(None)
+            }
+            (
+                _,
+                SessionState::Established | SessionState::Disconnecting,
+                SoePacket::SessionRequest(_) | SoePacket::SessionReply(_),
+            ) => Err(self.unexpected_packet(packet)),
+            (_, SessionState::Established | SessionState::Disconnecting, _) => Ok(None),
+            _ => Err(self.unexpected_packet(packet)),
+        }
+    }
+
+    pub fn disconnect(&mut self, reason: DisconnectReason) -> Result<Vec<u8>, ProtocolError> {
+        if self.state != SessionState::Established {
+            return Err(ProtocolError::Handshake(format!(
+                "cannot disconnect in {:?} state",
+                self.state
+            )));
+        }
+        self.state = SessionState::Disconnecting;
+        Ok(self.codec.pack_disconnect_packet(self.session_id, reason))
+    }
+
+    // encrypt reliable payloads with rc4 streams keyed from the session key
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), ProtocolError> {
+        self.cipher = Some(ReliableCipher::new(key)?);
+        Ok(())
+    }
+
+    pub fn disable_encryption(&mut self) {
+        self.cipher = None;
+    }
+
+    pub fn is_using_encryption(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    // encrypt the payload if needed then pack it on the sender channel
+    pub fn send_reliable(
+        &mut self,
+        sender: &mut ReliableSender,
+        payload: Vec<u8>,
+        now: u64,
+    ) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        self.check_established()?;
+        let payload = match self.cipher.as_mut() {
+            Some(cipher) => cipher.encrypt(payload),
+            None => payload,
+        };
+        sender.send_data(
+            &mut self.codec,
+            &payload,
+            self.udp_length,
+            self.crc_length,
+            now,
+        )
+    }
+
+    // hand the packet to the receiver and decrypt the reassembled payloads
+    pub fn receive_reliable(
+        &mut self,
+        receiver: &mut ReliableReceiver,
+        packet: DataPacket,
+        fragment: bool,
+        now: u64,
+    ) -> Result<ReceivedData, ProtocolError> {
+        self.check_established()?;
+        let mut received = receiver.on_data(&mut self.codec, packet, fragment, now)?;
+        if let Some(cipher) = self.cipher.as_mut() {
+            received.payloads = received
+                .payloads
+                .into_iter()
+                .map(|payload| cipher.decrypt(payload))
+                .collect();
+        }
+        Ok(received)
+    }
+
+    // call for every datagram received from the peer
+    pub fn record_received(&mut self, now: u64) {
+        self.last_received = Some(now);
+        self.net_status.record_received();
+    }
+
+    // call for every datagram sent to the peer
+    pub fn record_sent(&mut self) {
+        self.net_status.record_sent();
+    }
+
+    // client side, report our counters to the server
+    pub fn pack_net_status_request(&mut self, now: u64) -> Result<Vec<u8>, ProtocolError> {
+        self.check_established()?;
+        let request = self.net_status.build_request(now);
+        self.net_status_request = Some(request.clone());
+        let packet = self.codec.pack_net_status_request_object(request);
+        Ok(self.codec.finalize_packet(&packet))
+    }
+
+    // server side, answer with the client counters next to ours
+    pub fn handle_net_status_request(
+        &mut self,
+        request: &NetStatusRequestPacket,
+        now: u64,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        self.check_established()?;
+        let reply = self.net_status.build_reply(request, now);
+        let packet = self.codec.pack_net_status_reply_object(reply);
+        Ok(self.codec.finalize_packet(&packet))
+    }
+
+    pub fn handle_net_status_reply(
+        &mut self,
+        reply: &NetStatusReplyPacket,
+        now: u64,
+    ) -> Result<(), ProtocolError> {
+        self.check_established()?;
+        self.net_status.handle_reply(reply);
+        if let Some(request) = &self.net_status_request {
+            if self.clock_sync.add_sample(request, reply, now) {
+                self.net_status_request = None;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_clock_sync(&self) -> &ClockSync {
+        &self.clock_sync
+    }
+
+    pub fn get_net_status(&self) -> &NetStatusStats {
+        &self.net_status
+    }
+
+    // checks liveness, returns a ping to send or the disconnect sent when the peer timed out
+    pub fn poll<'a>(
+        &mut self,
+        senders: impl IntoIterator<Item = &'a ReliableSender>,
+        now: u64,
+    ) -> Option<Vec<u8>> {
+        match self.state {
+            SessionState::Established => {}
+            // the peer never answered our disconnect
+            SessionState::Disconnecting => {
+                if self.is_idle(now) {
+                    self.close();
+                }
+                return None;
+            }
+            _ => return None,
+        }
+        if self.is_idle(now) {
+            return Some(self.time_out(DisconnectReason::DisconnectReasonTimeout));
+        }
+        if self.keepalive.unacked_timeout > 0
+            && senders
+                .into_iter()
+                .filter_map(ReliableSender::get_oldest_unacked_since)
+                .any(|since| now.saturating_sub(since) >= self.keepalive.unacked_timeout)
+        {
+            return Some(self.time_out(DisconnectReason::DisconnectReasonUnacknowledgedTimeout));
+        }
+        if self.role == SessionRole::Client && self.keepalive.ping_interval > 0 {
+            let last_ping = *self.last_ping.get_or_insert(now);
+            if now.saturating_sub(last_ping) >= self.keepalive.ping_interval {
+                self.last_ping = Some(now);
+                return Some(self.pack_ping());
+            }
+        }
+        None
+    }
+
+    pub fn get_keepalive(&self) -> KeepaliveConfig {
+        self.keepalive
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: KeepaliveConfig) {
+        self.keepalive = keepalive;
+    }
+
+    pub fn get_last_received(&self) -> Option<u64> {
+        self.last_received
+    }
+
+    pub fn close(&mut self) {
+        self.state = SessionState::Closed;
+    }
+
+    pub fn parse_packet(&self, data: &[u8]) -> Result<SoePacket, ProtocolError> {
+        self.codec.parse_packet(data)
+    }
+
+    pub fn get_role(&self) -> SessionRole {
+        self.role
+    }
+
+    pub fn get_state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state == SessionState::Established
+    }
+
+    pub fn get_session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    pub fn get_crc_seed(&self) -> u32 {
+        self.crc_seed
+    }
+
+    pub fn get_crc_length(&self) -> u8 {
+        self.crc_length
+    }
+
+    pub fn get_encrypt_method(&self) -> u16 {
+        self.encrypt_method
+    }
+
+    // server side, the method is announced in the session reply
+    pub fn set_encrypt_method(&mut self, encrypt_method: u16) {
+        self.encrypt_method = encrypt_method;
+    }
+
+    pub fn get_udp_length(&self) -> u32 {
+        self.udp_length
+    }
+
+    pub fn get_codec(&self) -> &Soeprotocol {
+        &self.codec
+    }
+
+    pub fn get_codec_mut(&mut self) -> &mut Soeprotocol {
+        &mut self.codec
+    }
+
+    fn pack_session_reply(&mut self) -> Vec<u8> {
+        self.codec
+            .pack_session_reply_object(SessionReplyPacket::new(
+                self.session_id,
+                self.crc_seed,
+                self.crc_length,
+                self.encrypt_method,
+                self.udp_length,
+            ))
+    }
+
+    fn pack_ping(&self) -> Vec<u8> {
+        self.codec.finalize_packet(&SoePacket::Ping.pack_to_vec())
+    }
+
+    // the peer is gone, no need to wait for its disconnect
+    fn is_idle(&mut self, now: u64) -> bool {
+        let last_received = *self.last_received.get_or_insert(now);
+        self.keepalive.idle_timeout > 0
+            && now.saturating_sub(last_received) >= self.keepalive.idle_timeout
+    }
+
+    fn time_out(&mut self, reason: DisconnectReason) -> Vec<u8> {
+        self.state = SessionState::Closed;
+        self.codec.pack_disconnect_packet(self.session_id, reason)
+    }
+
+    fn configure_codec(&mut self) -> Result<(), ProtocolError> {
+        self.codec.set_crc_length(self.crc_length)?;
+        self.codec.set_encrypt_method(self.encrypt_method)?;
+        self.codec.set_crc_seed(self.crc_seed);
+        if self.crc_length > 0 {
+            self.codec.enable_crc();
+        } else {
+            self.codec.disable_crc();
+        }
+        Ok(())
+    }
+
+    fn check_established(&self) -> Result<(), ProtocolError> {
+        if self.state != SessionState::Established {
+            return Err(ProtocolError::Handshake(format!(
+                "cannot exchange data in {:?} state",
+                self.state
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_session_id(&self, session_id: u32) -> Result<(), ProtocolError> {
+        if session_id != self.session_id {
+            return Err(ProtocolError::Handshake(format!(
+                "session id {} does not match {}",
+                session_id, self.session_id
+            )));
+        }
+        Ok(())
+    }
+
+    fn unexpected_packet(&self, packet: &SoePacket) -> ProtocolError {
+        ProtocolError::UnexpectedPacket {
+            state: format!("{:?}", self.state),
+            packet: packet.get_name().to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::soeprotocol_packets_structs::{AckPacket, DisconnectPacket, SoePacket};
+    use super::*;
+
+    fn handshake() -> (SoeSession, SoeSession) {
+        let mut client = SoeSession::client("LoginUdp_9", 512);
+        let mut server = SoeSession::server("LoginUdp_9", 1024, DEFAULT_CRC_LENGTH);
+        let request = client.connect().unwrap();
+        let request = server.parse_packet(&request).unwrap();
+        let reply = server.handle_packet(&request).unwrap().unwrap();
+        let reply = client.parse_packet(&reply).unwrap();
+        assert_eq!(client.handle_packet(&reply), Ok(None));
+        (client, server)
+    }
+
+    #[test]
+    fn session_handshake_test() {
+        let (client, server) = handshake();
+        assert!(client.is_established());
+        assert!(server.is_established());
+        assert_eq!(client.get_session_id(), server.get_session_id());
+        assert_eq!(client.get_crc_seed(), server.get_crc_seed());
+        assert_eq!(client.get_crc_length(), DEFAULT_CRC_LENGTH);
+        assert_eq!(client.get_udp_length(), 512);
+        assert!(client.get_codec().is_using_crc());
+        assert!(server.get_codec().is_using_crc());
+    }
+
+    #[test]
+    fn session_resend_reply_test() {
+        let mut server = SoeSession::server("LoginUdp_9", 512, DEFAULT_CRC_LENGTH);
+        let request = SoePacket::SessionRequest(SessionRequestPacket::new(
+            7,
+            PROTOCOL_VERSION,
+            512,
+            "LoginUdp_9".to_owned(),
+        ));
+        let reply = server.handle_packet(&request).unwrap();
+        assert_eq!(server.handle_packet(&request).unwrap(), reply)
+    }
+
+    #[test]
+    fn session_protocol_mismatch_test() {
+        let mut server = SoeSession::server("LoginUdp_9", 512, DEFAULT_CRC_LENGTH);
+        let request = SoePacket::SessionRequest(SessionRequestPacket::new(
+            7,
+            PROTOCOL_VERSION,
+            512,
+            "ExternalGatewayApi_3".to_owned(),
+        ));
+        assert!(matches!(
+            server.handle_packet(&request),
+            Err(ProtocolError::Handshake(_))
+        ));
+        assert_eq!(server.get_state(), SessionState::Idle)
+    }
+
+    #[test]
+    fn session_reply_mismatch_test() {
+        let mut client = SoeSession::client("LoginUdp_9", 512);
+        client.connect().unwrap();
+        let reply = SoePacket::SessionReply(SessionReplyPacket::new(
+            client.get_session_id().wrapping_add(1),
+            0,
+            2,
+            0,
+            512,
+        ));
+        assert!(matches!(
+            client.handle_packet(&reply),
+            Err(ProtocolError::Handshake(_))
+        ));
+        assert_eq!(client.get_state(), SessionState::Requested)
+    }
+
+    #[test]
+    fn session_out_of_state_test() {
+        let mut client = SoeSession::client("LoginUdp_9", 512);
+        assert_eq!(
+            client.handle_packet(&SoePacket::Ack(AckPacket::new(0))),
+            Err(ProtocolError::UnexpectedPacket {
+                state: "Idle".to_owned(),
+                packet: "Ack".to_owned(),
+            })
+        );
+        let mut server = SoeSession::server("LoginUdp_9", 512, DEFAULT_CRC_LENGTH);
+        assert!(server.connect().is_err());
+        let reply = SoePacket::SessionReply(SessionReplyPacket::new(0, 0, 2, 0, 512));
+        assert!(matches!(
+            server.handle_packet(&reply),
+            Err(ProtocolError::UnexpectedPacket { .. })
+        ))
+    }
+
+    #[test]
+    fn session_disconnect_test() {
+        let (mut client, mut server) = handshake();
+        assert_eq!(
+            server.handle_packet(&SoePacket::Ack(AckPacket::new(0))),
+            Ok(None)
+        );
+        let disconnect = client
+            .disconnect(DisconnectReason::DisconnectReasonApplication)
+            .unwrap();
+        assert_eq!(client.get_state(), SessionState::Disconnecting);
+        let disconnect = server.parse_packet(&disconnect).unwrap();
+        assert_eq!(server.handle_packet(&disconnect), Ok(None));
+        assert_eq!(server.get_state(), SessionState::Closed);
+        assert!(matches!(
+            server.handle_packet(&SoePacket::Ping),
+            Err(ProtocolError::UnexpectedPacket { .. })
+        ))
+    }
+
+    #[test]
+    fn session_encrypted_reliable_test() {
+        let (mut client, mut server) = handshake();
+        let key = [23, 189, 8, 107, 27, 148, 240, 47];
+        client.enable_encryption(&key).unwrap();
+        server.enable_encryption(&key).unwrap();
+        let mut sender = ReliableSender::new();
+        let mut receiver = ReliableReceiver::new();
+        let payloads = [vec![0, 21, 1, 2], vec![7; 1200]];
+        let mut delivered = vec![];
+        for payload in &payloads {
+            let packets = client
+                .send_reliable(&mut sender, payload.clone(), 0)
+                .unwrap();
+            for packet in packets {
+                let packet = client.get_codec().finalize_packet(&packet);
+                let (packet, fragment) = match server.parse_packet(&packet).unwrap() {
+                    SoePacket::Data(packet) => (packet, false),
+                    SoePacket::DataFragment(packet) => (packet, true),
+                    packet => panic!("unexpected {:?}", packet),
+                };
+                let received = server
+                    .receive_reliable(&mut receiver, packet, fragment, 0)
+                    .unwrap();
+                delivered.extend(received.payloads);
+            }
+        }
+        assert_eq!(delivered, payloads.to_vec())
+    }
+
+    #[test]
+    fn session_encrypt_method_test() {
+        let mut client = SoeSession::client("LoginUdp_9", 512);
+        let mut server = SoeSession::server("LoginUdp_9", 512, DEFAULT_CRC_LENGTH);
+        server.set_encrypt_method(EncryptMethod::EncryptMethodXor as u16);
+        let request = client.connect().unwrap();
+        let request = server.parse_packet(&request).unwrap();
+        let reply = server.handle_packet(&request).unwrap().unwrap();
+        let reply = client.parse_packet(&reply).unwrap();
+        client.handle_packet(&reply).unwrap();
+        assert_eq!(
+            client.get_codec().get_encrypt_method(),
+            EncryptMethod::EncryptMethodXor as u16
+        );
+        let packet = client
+            .get_codec_mut()
+            .pack_data_packet(vec![1, 2, 3, 4, 5], 0);
+        let packet = client.get_codec().finalize_packet(&packet);
+        assert_eq!(
+            server.parse_packet(&packet),
+            Ok(SoePacket::Data(DataPacket::new(vec![1, 2, 3, 4, 5], 0)))
+        )
+    }
+
+    #[test]
+    fn session_unsupported_encrypt_method_test() {
+        let mut client = SoeSession::client("LoginUdp_9", 512);
+        client.connect().unwrap();
+        let reply = SoePacket::SessionReply(SessionReplyPacket::new(
+            client.get_session_id(),
+            0,
+            2,
+            EncryptMethod::EncryptMethodUserSupplied as u16,
+            512,
+        ));
+        assert!(matches!(
+            client.handle_packet(&reply),
+            Err(ProtocolError::Handshake(_))
+        ));
+        assert_eq!(client.get_state(), SessionState::Requested)
+    }
+
+    #[test]
+    fn session_ping_test() {
+        let (mut client, mut server) = handshake();
+        assert_eq!(client.poll([], 0), None);
+        let ping = client.poll([], DEFAULT_PING_INTERVAL).unwrap();
+        assert_eq!(client.poll([], DEFAULT_PING_INTERVAL + 1), None);
+        let ping = server.parse_packet(&ping).unwrap();
+        assert_eq!(ping, SoePacket::Ping);
+        let pong = server.handle_packet(&ping).unwrap().unwrap();
+        assert_eq!(client.parse_packet(&pong), Ok(SoePacket::Ping));
+        // servers don't ping on their own
+        assert_eq!(server.poll([], 0), None);
+        server.record_received(DEFAULT_PING_INTERVAL);
+        assert_eq!(server.poll([], 2 * DEFAULT_PING_INTERVAL), None)
+    }
+
+    #[test]
+    fn session_idle_timeout_test() {
+        let (client, mut server) = handshake();
+        server.record_received(100);
+        assert_eq!(server.poll([], DEFAULT_IDLE_TIMEOUT), None);
+        let disconnect = server.poll([], DEFAULT_IDLE_TIMEOUT + 100).unwrap();
+        assert_eq!(server.get_state(), SessionState::Closed);
+        assert_eq!(
+            client.parse_packet(&disconnect).unwrap().to_json(),
+            format!(
+                r#"{{"name":"Disconnect","session_id":{},"reason":"DisconnectReasonTimeout","reason_code":1}}"#,
+                server.get_session_id()
+            )
+        );
+        assert_eq!(server.poll([], DEFAULT_IDLE_TIMEOUT + 200), None)
+    }
+
+    #[test]
+    fn session_disconnecting_idle_timeout_test() {
+        let (_, mut server) = handshake();
+        server.record_received(100);
+        server
+            .disconnect(DisconnectReason::DisconnectReasonApplication)
+            .unwrap();
+        assert_eq!(server.poll([], DEFAULT_IDLE_TIMEOUT), None);
+        assert_eq!(server.get_state(), SessionState::Disconnecting);
+        assert_eq!(server.poll([], DEFAULT_IDLE_TIMEOUT + 100), None);
+        assert_eq!(server.get_state(), SessionState::Closed)
+    }
+
+    #[test]
+    fn session_unacked_timeout_test() {
+        let (mut client, server) = handshake();
+        client.set_keepalive(KeepaliveConfig {
+            ping_interval: 0,
+            idle_timeout: 0,
+            unacked_timeout: 1000,
+        });
+        let mut sender = ReliableSender::new();
+        client.send_reliable(&mut sender, vec![1], 10).unwrap();
+        assert_eq!(client.poll([&sender], 1009), None);
+        let disconnect = client.poll([&sender], 1010).unwrap();
+        assert_eq!(
+            server.parse_packet(&disconnect),
+            Ok(SoePacket::Disconnect(Some(DisconnectPacket::new(
+                client.get_session_id(),
+                DisconnectReason::DisconnectReasonUnacknowledgedTimeout as u16
+            ))))
+        )
+    }
+
+    #[test]
+    fn session_net_status_test() {
+        let (mut client, mut server) = handshake();
+        client.record_sent();
+        server.record_received(0);
+        let request = client.pack_net_status_request(1000).unwrap();
+        let SoePacket::NetStatusRequest(request) = server.parse_packet(&request).unwrap() else {
+            panic!("expected a net status request")
+        };
+        assert_eq!(request.packets_sent, 1);
+        let reply = server.handle_net_status_request(&request, 2000).unwrap();
+        let SoePacket::NetStatusReply(reply) = client.parse_packet(&reply).unwrap() else {
+            panic!("expected a net status reply")
+        };
+        assert_eq!(reply.client_tick_count, 1000);
+        assert_eq!(reply.server_tick_count, 2000);
+        assert_eq!(reply.client_packet_sent, 1);
+        assert_eq!(reply.server_packet_received, 1);
+        client.handle_net_status_reply(&reply, 1040).unwrap();
+        assert_eq!(client.get_net_status().get_packets_sent(), 1);
+        assert_eq!(client.get_clock_sync().get_srtt(), Some(40));
+        assert_eq!(client.get_clock_sync().get_offset(), Some(980))
+    }
+}
@@ -0,0 +1,371 @@
+use super::protocol_errors::ProtocolError;
+use super::soeprotocol::Soeprotocol;
+use super::soeprotocol_batcher::MultiPacketBatcher;
+use super::soeprotocol_packets_structs::SoePacket;
+use super::soeprotocol_reliable::ReliableChannels;
+use super::soeprotocol_session::{KeepaliveConfig, SessionState, SoeSession};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+// a session with its reliable channels and the batcher its packets leave through
+pub struct PeerSession {
+    pub session: SoeSession,
+    pub channels: ReliableChannels,
+    // flushed by the caller, see ReliableChannels::flush_with_acks
+    pub batcher: MultiPacketBatcher,
+}
+
+impl PeerSession {
+    // the batcher follows the udp length negotiated so far
+    pub fn new(session: SoeSession) -> Self {
+        let batcher = MultiPacketBatcher::new(session.get_udp_length(), 0);
+        Self {
+            session,
+            channels: ReliableChannels::new(),
+            batcher,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IncomingPacket {
+    pub packet: SoePacket,
+    // packet to send back to the peer, if any
+    pub reply: Option<Vec<u8>>,
+    // the datagram opened a new session
+    pub created: bool,
+    // id of the session dropped because its peer asked for a new one
+    pub replaced: Option<u32>,
+}
+
+// a session request for another session id than the one the peer has
+fn is_new_connection_attempt(packet: &SoePacket, session_id: u32) -> bool {
+    matches!(packet, SoePacket::SessionRequest(request) if request.session_id != session_id)
+}
+
+// owns the sessions of a server, keyed by peer address and session id
+pub struct SessionManager {
+    protocol: String,
+    udp_length: u32,
+    crc_length: u8,
+    encrypt_method: u16,
+    keepalive: KeepaliveConfig,
+    // session requests are parsed before any crc is negotiated
+    codec: Soeprotocol,
+    sessions: HashMap<SocketAddr, PeerSession>,
+    session_ids: HashMap<u32, SocketAddr>,
+}
+
+impl SessionManager {
+    pub fn new(protocol: &str, udp_length: u32, crc_length: u8) -> Self {
+        Self {
+            protocol: protocol.to_owned(),
+            udp_length,
+            crc_length,
+            encrypt_method: 0,
+            keepalive: KeepaliveConfig::default(),
+            codec: Soeprotocol::initialize(false, 0),
+            sessions: HashMap::new(),
+            session_ids: HashMap::new(),
+        }
+    }
+
+    // routes the datagram to the peer session, a session request from a new peer opens one
+    pub fn handle_datagram(
+        &mut self,
+        addr: SocketAddr,
+        data: &[u8],
+        now: u64,
+    ) -> Result<IncomingPacket, ProtocolError> {
+        let mut replaced = None;
+        let packet = match self.sessions.get_mut(&addr) {
+            Some(peer) => {
+                let packet = peer.session.parse_packet(data)?;
+                let session_id = peer.session.get_session_id();
+                if !is_new_connection_attempt(&packet, session_id) {
+                    peer.session.record_received(now);
+                    let reply = peer.session.handle_packet(&packet)?;
+                    if peer.session.get_state() == SessionState::Closed {
+                        self.remove(&addr);
+                    }
+                    return Ok(IncomingPacket {
+                        packet,
+                        reply,
+                        created: false,
+                        replaced: None,
+                    });
+                }
+                // the peer restarted, the old session is dropped as DisconnectReasonNewConnectionAttempt
+                // once the new one is accepted
+                replaced = Some(session_id);
+                packet
+            }
+            None => self.codec.parse_packet(data)?,
+        };
+        let SoePacket::SessionRequest(request) = &packet else {
+            return Err(ProtocolError::UnexpectedPacket {
+                state: "Unknown".to_owned(),
+                packet: packet.get_name().to_owned(),
+            });
+        };
+        if self.session_ids.contains_key(&request.session_id) {
+            return Err(ProtocolError::Handshake(format!(
+                "session id {} is already in use",
+                request.session_id
+            )));
+        }
+        let mut session = SoeSession::server(&self.protocol, self.udp_length, self.crc_length);
+        session.set_encrypt_method(self.encrypt_method);
+        session.set_keepalive(self.keepalive);
+        let reply = session.handle_packet(&packet)?;
+        session.record_received(now);
+        if replaced.is_some() {
+            self.remove(&addr);
+        }
+        self.session_ids.insert(session.get_session_id(), addr);
+        self.sessions.insert(addr, PeerSession::new(session));
+        Ok(IncomingPacket {
+            packet,
+            reply,
+            created: true,
+            replaced,
+        })
+    }
+
+    // keepalive checks, returns the packets to send and drops the sessions that timed out
+    pub fn poll(&mut self, now: u64) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut packets = vec![];
+        for (addr, peer) in self.sessions.iter_mut() {
+            if let Some(packet) = peer.session.poll(peer.channels.senders(), now) {
+                packets.push((*addr, packet));
+            }
+        }
+        self.evict_closed();
+        packets
+    }
+
+    // drops the closed sessions and returns their addresses
+    pub fn evict_closed(&mut self) -> Vec<SocketAddr> {
+        let closed: Vec<SocketAddr> = self
+            .sessions
+            .iter()
+            .filter(|(_, peer)| peer.session.get_state() == SessionState::Closed)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &closed {
+            self.remove(addr);
+        }
+        closed
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<PeerSession> {
+        let peer = self.sessions.remove(addr)?;
+        self.session_ids.remove(&peer.session.get_session_id());
+        Some(peer)
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerSession> {
+        self.sessions.get(addr)
+    }
+
+    pub fn get_mut(&mut self, addr: &SocketAddr) -> Option<&mut PeerSession> {
+        self.sessions.get_mut(addr)
+    }
+
+    pub fn get_addr(&self, session_id: u32) -> Option<SocketAddr> {
+        self.session_ids.get(&session_id).copied()
+    }
+
+    pub fn get_by_session_id_mut(&mut self, session_id: u32) -> Option<&mut PeerSession> {
+        let addr = self.get_addr(session_id)?;
+        self.sessions.get_mut(&addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &PeerSession)> {
+        self.sessions.iter()
+    }
+
+    // used to flush the outbound queues of every session
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&SocketAddr, &mut PeerSession)> {
+        self.sessions.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    // applies to the sessions created from now on
+    pub fn set_encrypt_method(&mut self, encrypt_method: u16) {
+        self.encrypt_method = encrypt_method;
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: KeepaliveConfig) {
+        self.keepalive = keepalive;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::soeprotocol::DisconnectReason;
+    use super::super::soeprotocol_packets_structs::SessionRequestPacket;
+    use super::super::soeprotocol_session::{DEFAULT_IDLE_TIMEOUT, PROTOCOL_VERSION};
+    use super::*;
+
+    fn connect(manager: &mut SessionManager, addr: SocketAddr) -> SoeSession {
+        let mut client = SoeSession::client("LoginUdp_9", 512);
+        let request = client.connect().unwrap();
+        let incoming = manager.handle_datagram(addr, &request, 0).unwrap();
+        assert!(incoming.created);
+        let reply = client.parse_packet(&incoming.reply.unwrap()).unwrap();
+        client.handle_packet(&reply).unwrap();
+        client
+    }
+
+    #[test]
+    fn session_manager_routing_test() {
+        let mut manager = SessionManager::new("LoginUdp_9", 512, 2);
+        let first_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let second_addr: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        let mut first = connect(&mut manager, first_addr);
+        let second = connect(&mut manager, second_addr);
+        assert_eq!(manager.len(), 2);
+        assert_eq!(manager.get_addr(second.get_session_id()), Some(second_addr));
+        assert_eq!(
+            manager.get(&first_addr).unwrap().session.get_crc_seed(),
+            first.get_crc_seed()
+        );
+        let data = first.get_codec_mut().pack_data_packet(vec![1, 2], 0);
+        let data = first.get_codec().finalize_packet(&data);
+        let incoming = manager.handle_datagram(first_addr, &data, 10).unwrap();
+        assert!(!incoming.created);
+        assert!(matches!(incoming.packet, SoePacket::Data(_)));
+        assert!(manager
+            .get_by_session_id_mut(first.get_session_id())
+            .is_some_and(|peer| peer.session.get_last_received() == Some(10)));
+        assert_eq!(manager.iter_mut().count(), 2)
+    }
+
+    #[test]
+    fn session_manager_unknown_peer_test() {
+        let mut manager = SessionManager::new("LoginUdp_9", 512, 2);
+        let addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        assert_eq!(
+            manager.handle_datagram(addr, &[0, 21, 0, 1], 0),
+            Err(ProtocolError::UnexpectedPacket {
+                state: "Unknown".to_owned(),
+                packet: "Ack".to_owned(),
+            })
+        );
+        let client = connect(&mut manager, addr);
+        let duplicate = manager
+            .codec
+            .pack_session_request_object(SessionRequestPacket::new(
+                client.get_session_id(),
+                PROTOCOL_VERSION,
+                512,
+                "LoginUdp_9".to_owned(),
+            ));
+        let other_addr: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        assert!(matches!(
+            manager.handle_datagram(other_addr, &duplicate, 0),
+            Err(ProtocolError::Handshake(_))
+        ))
+    }
+
+    #[test]
+    fn session_manager_eviction_test() {
+        let mut manager = SessionManager::new("LoginUdp_9", 512, 2);
+        let first_addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let second_addr: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        let mut first = connect(&mut manager, first_addr);
+        let second = connect(&mut manager, second_addr);
+        let disconnect = first
+            .disconnect(DisconnectReason::DisconnectReasonApplication)
+            .unwrap();
+        manager
+            .handle_datagram(first_addr, &disconnect, 10)
+            .unwrap();
+        assert!(manager.get(&first_addr).is_none());
+        assert_eq!(manager.get_addr(first.get_session_id()), None);
+        let packets = manager.poll(DEFAULT_IDLE_TIMEOUT);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].0, second_addr);
+        assert!(matches!(
+            second.parse_packet(&packets[0].1),
+            Ok(SoePacket::Disconnect(Some(_)))
+        ));
+        assert!(manager.is_empty())
+    }
+
+    #[test]
+    fn session_manager_new_connection_attempt_test() {
+        let mut manager = SessionManager::new("LoginUdp_9", 512, 2);
+        let addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let old = connect(&mut manager, addr);
+        let mut client = SoeSession::client("LoginUdp_9", 512);
+        let request = client.connect().unwrap();
+        let incoming = manager.handle_datagram(addr, &request, 10).unwrap();
+        assert!(incoming.created);
+        assert_eq!(incoming.replaced, Some(old.get_session_id()));
+        assert_eq!(manager.len(), 1);
+        assert_eq!(manager.get_addr(old.get_session_id()), None);
+        assert_eq!(manager.get_addr(client.get_session_id()), Some(addr));
+        // asking again for the same session only resends the reply
+        let incoming = manager.handle_datagram(addr, &request, 20).unwrap();
+        assert!(!incoming.created);
+        assert_eq!(incoming.replaced, None);
+        assert!(matches!(
+            client.parse_packet(&incoming.reply.unwrap()),
+            Ok(SoePacket::SessionReply(_))
+        ))
+    }
+
+    #[test]
+    fn session_manager_bad_new_connection_attempt_test() {
+        let mut manager = SessionManager::new("LoginUdp_9", 512, 2);
+        let addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let other_addr: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+        let old = connect(&mut manager, addr);
+        let other = connect(&mut manager, other_addr);
+        let mut client = SoeSession::client("LoginUdp_10", 512);
+        let request = client.connect().unwrap();
+        assert!(matches!(
+            manager.handle_datagram(addr, &request, 10),
+            Err(ProtocolError::Handshake(_))
+        ));
+        let taken = manager
+            .codec
+            .pack_session_request_object(SessionRequestPacket::new(
+                other.get_session_id(),
+                PROTOCOL_VERSION,
+                512,
+                "LoginUdp_9".to_owned(),
+            ));
+        assert!(matches!(
+            manager.handle_datagram(addr, &taken, 10),
+            Err(ProtocolError::Handshake(_))
+        ));
+        assert_eq!(manager.len(), 2);
+        assert_eq!(manager.get_addr(old.get_session_id()), Some(addr));
+        assert!(manager.get(&addr).unwrap().session.is_established())
+    }
+
+    #[test]
+    fn session_manager_disconnecting_eviction_test() {
+        let mut manager = SessionManager::new("LoginUdp_9", 512, 2);
+        let addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        connect(&mut manager, addr);
+        manager
+            .get_mut(&addr)
+            .unwrap()
+            .session
+            .disconnect(DisconnectReason::DisconnectReasonApplication)
+            .unwrap();
+        assert!(manager.poll(DEFAULT_IDLE_TIMEOUT).is_empty());
+        assert!(manager.is_empty())
+    }
+}
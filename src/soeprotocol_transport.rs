@@ -0,0 +1,624 @@
+use super::protocol_errors::ProtocolError;
+use super::soeprotocol::{DisconnectReason, RELIABLE_CHANNEL_COUNT};
+use super::soeprotocol_batcher::MultiPacketBatcher;
+use super::soeprotocol_packets_structs::{DataPacket, SoePacket};
+use super::soeprotocol_reliable::ReliableChannels;
+use super::soeprotocol_session::{SessionState, SoeSession};
+use super::soeprotocol_session_manager::{PeerSession, SessionManager};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+// longest a blocking call waits on the socket before running the timers again
+const TICK: Duration = Duration::from_millis(10);
+// the session request is sent again when no reply came in that time
+const CONNECT_RETRY: Duration = Duration::from_millis(500);
+const MAX_DATAGRAM_SIZE: usize = 0x10000;
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(std::io::Error),
+    Protocol(ProtocolError),
+    // no session reply before the connect timeout
+    Timeout,
+    // the peer disconnected or timed out
+    Closed,
+    UnknownPeer(SocketAddr),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(error) => write!(f, "socket error: {}", error),
+            TransportError::Protocol(error) => write!(f, "{}", error),
+            TransportError::Timeout => write!(f, "no session reply from the server"),
+            TransportError::Closed => write!(f, "the session is closed"),
+            TransportError::UnknownPeer(addr) => write!(f, "no session with {}", addr),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<std::io::Error> for TransportError {
+    fn from(error: std::io::Error) -> Self {
+        TransportError::Io(error)
+    }
+}
+
+impl From<ProtocolError> for TransportError {
+    fn from(error: ProtocolError) -> Self {
+        TransportError::Protocol(error)
+    }
+}
+
+fn is_timeout(error: &std::io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// queue a packed packet on the peer batcher, the frames it had to send go to outgoing
+fn queue(peer: &mut PeerSession, packet: Vec<u8>, now: u64, outgoing: &mut Vec<Vec<u8>>) {
+    outgoing.extend(peer.batcher.push(peer.session.get_codec(), packet, now));
+}
+
+// feeds the reliable part of a packet to the channels, collects payloads and queues the replies
+fn handle_reliable(
+    peer: &mut PeerSession,
+    packet: SoePacket,
+    now: u64,
+    outgoing: &mut Vec<Vec<u8>>,
+    payloads: &mut Vec<Vec<u8>>,
+) -> Result<(), ProtocolError> {
+    match packet {
+        SoePacket::Multi(packets) | SoePacket::Group(packets) => {
+            for packet in packets {
+                handle_reliable(peer, packet, now, outgoing, payloads)?;
+            }
+        }
+        SoePacket::Data(data) => receive_data(peer, data, false, now, outgoing, payloads)?,
+        SoePacket::DataFragment(data) => receive_data(peer, data, true, now, outgoing, payloads)?,
+        SoePacket::Ack(ack) => {
+            if let Some(sender) = peer.channels.get_sender(ack.get_channel()) {
+                sender.on_ack(ack.sequence, now);
+            }
+        }
+        SoePacket::OutOfOrder(ack) => {
+            if let Some(sender) = peer.channels.get_sender(ack.get_channel()) {
+                sender.on_out_of_order(ack.sequence, now);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn receive_data(
+    peer: &mut PeerSession,
+    data: DataPacket,
+    fragment: bool,
+    now: u64,
+    outgoing: &mut Vec<Vec<u8>>,
+    payloads: &mut Vec<Vec<u8>>,
+) -> Result<(), ProtocolError> {
+    let Some(receiver) = peer.channels.get_receiver(data.get_channel()) else {
+        return Ok(());
+    };
+    let received = peer
+        .session
+        .receive_reliable(receiver, data, fragment, now)?;
+    for reply in received.replies {
+        queue(peer, reply, now, outgoing);
+    }
+    payloads.extend(received.payloads);
+    Ok(())
+}
+
+// queue the due resends then flush the batcher with the pending acks folded in
+fn flush_peer(peer: &mut PeerSession, now: u64, outgoing: &mut Vec<Vec<u8>>) {
+    if !peer.session.is_established() {
+        return;
+    }
+    for channel in 0..RELIABLE_CHANNEL_COUNT {
+        let Some(sender) = peer.channels.get_sender(channel) else {
+            continue;
+        };
+        for packet in sender.get_packets_to_resend(now) {
+            queue(peer, packet, now, outgoing);
+        }
+    }
+    outgoing.extend(peer.channels.flush_with_acks(
+        peer.session.get_codec_mut(),
+        &mut peer.batcher,
+        now,
+    ));
+}
+
+fn send_reliable(
+    peer: &mut PeerSession,
+    payload: &[u8],
+    now: u64,
+) -> Result<Vec<Vec<u8>>, TransportError> {
+    if !peer.session.is_established() {
+        return Err(TransportError::Closed);
+    }
+    let Some(sender) = peer.channels.get_sender(0) else {
+        return Ok(vec![]);
+    };
+    let packets = peer.session.send_reliable(sender, payload.to_vec(), now)?;
+    let mut outgoing = vec![];
+    for packet in packets {
+        queue(peer, packet, now, &mut outgoing);
+    }
+    flush_peer(peer, now, &mut outgoing);
+    Ok(outgoing)
+}
+
+// blocking client over a udp socket, drives the session and its reliable channels
+pub struct SoeClient {
+    socket: UdpSocket,
+    server_addr: SocketAddr,
+    peer: PeerSession,
+    clock: Instant,
+    received: VecDeque<Vec<u8>>,
+    buffer: Vec<u8>,
+    // datagrams refused by the protocol layer
+    dropped: u64,
+}
+
+impl SoeClient {
+    // binds an ephemeral port and runs the handshake, the request is repeated until the reply arrives
+    pub fn connect(
+        server_addr: SocketAddr,
+        protocol: &str,
+        udp_length: u32,
+        timeout: Duration,
+    ) -> Result<Self, TransportError> {
+        let local_addr: SocketAddr = if server_addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.set_read_timeout(Some(TICK))?;
+        let mut client = Self {
+            socket,
+            server_addr,
+            peer: PeerSession::new(SoeSession::client(protocol, udp_length)),
+            clock: Instant::now(),
+            received: VecDeque::new(),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+            dropped: 0,
+        };
+        let request = client.peer.session.connect()?;
+        let deadline = Instant::now() + timeout;
+        let mut next_request = Instant::now();
+        while !client.peer.session.is_established() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TransportError::Timeout);
+            }
+            if now >= next_request {
+                client.send_datagram(&request)?;
+                next_request = now + CONNECT_RETRY;
+            }
+            client.receive_datagram()?;
+        }
+        // the server may have lowered the udp length
+        client.peer.batcher = MultiPacketBatcher::new(client.peer.session.get_udp_length(), 0);
+        Ok(client)
+    }
+
+    // sends the payload on reliable channel 0, fragmented if needed
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), TransportError> {
+        let now = self.now();
+        for packet in send_reliable(&mut self.peer, payload, now)? {
+            self.send_datagram(&packet)?;
+        }
+        Ok(())
+    }
+
+    // waits up to timeout for the next application payload, keeping the session alive meanwhile
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, TransportError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(payload) = self.received.pop_front() {
+                return Ok(Some(payload));
+            }
+            self.update()?;
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            self.receive_datagram()?;
+        }
+    }
+
+    // sends the due pings, resends and acks, fails once the session is closed
+    pub fn update(&mut self) -> Result<(), TransportError> {
+        let now = self.now();
+        let mut outgoing = vec![];
+        if let Some(packet) = self.peer.session.poll(self.peer.channels.senders(), now) {
+            outgoing.push(packet);
+        }
+        flush_peer(&mut self.peer, now, &mut outgoing);
+        for packet in outgoing {
+            self.send_datagram(&packet)?;
+        }
+        if self.peer.session.get_state() == SessionState::Closed {
+            return Err(TransportError::Closed);
+        }
+        Ok(())
+    }
+
+    // reports our counters to the server, the reply feeds the clock sync
+    pub fn send_net_status_request(&mut self) -> Result<(), TransportError> {
+        let now = self.now();
+        let packet = self.peer.session.pack_net_status_request(now)?;
+        self.send_datagram(&packet)
+    }
+
+    pub fn disconnect(mut self) -> Result<(), TransportError> {
+        let packet = self
+            .peer
+            .session
+            .disconnect(DisconnectReason::DisconnectReasonApplication)?;
+        self.send_datagram(&packet)
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn get_server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    pub fn get_session(&self) -> &SoeSession {
+        &self.peer.session
+    }
+
+    pub fn get_channels(&self) -> &ReliableChannels {
+        &self.peer.channels
+    }
+
+    pub fn get_dropped_count(&self) -> u64 {
+        self.dropped
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.elapsed().as_millis() as u64
+    }
+
+    fn send_datagram(&mut self, packet: &[u8]) -> Result<(), TransportError> {
+        self.socket.send_to(packet, self.server_addr)?;
+        self.peer.session.record_sent();
+        Ok(())
+    }
+
+    // handles at most one datagram, returns once the socket read times out
+    fn receive_datagram(&mut self) -> Result<(), TransportError> {
+        let (length, addr) = match self.socket.recv_from(&mut self.buffer) {
+            Ok(received) => received,
+            Err(error) if is_timeout(&error) => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        // anyone can write to the port
+        if addr != self.server_addr {
+            return Ok(());
+        }
+        let now = self.now();
+        let mut outgoing = vec![];
+        let mut payloads = vec![];
+        // a bad datagram is dropped, what it delivered before failing is kept
+        if self
+            .handle_datagram(length, now, &mut outgoing, &mut payloads)
+            .is_err()
+        {
+            self.dropped += 1;
+        }
+        self.received.extend(payloads);
+        for packet in outgoing {
+            self.send_datagram(&packet)?;
+        }
+        Ok(())
+    }
+
+    fn handle_datagram(
+        &mut self,
+        length: usize,
+        now: u64,
+        outgoing: &mut Vec<Vec<u8>>,
+        payloads: &mut Vec<Vec<u8>>,
+    ) -> Result<(), ProtocolError> {
+        let session = &mut self.peer.session;
+        let packet = session.parse_packet(&self.buffer[..length])?;
+        session.record_received(now);
+        if let Some(reply) = session.handle_packet(&packet)? {
+            outgoing.push(reply);
+        }
+        if let SoePacket::NetStatusReply(reply) = &packet {
+            session.handle_net_status_reply(reply, now)?;
+        }
+        if session.is_established() {
+            handle_reliable(&mut self.peer, packet, now, outgoing, payloads)?;
+            flush_peer(&mut self.peer, now, outgoing);
+        }
+        Ok(())
+    }
+}
+
+// blocking server over a udp socket, one session per peer address
+pub struct SoeServer {
+    socket: UdpSocket,
+    manager: SessionManager,
+    clock: Instant,
+    received: VecDeque<(SocketAddr, Vec<u8>)>,
+    buffer: Vec<u8>,
+    // datagrams refused by the protocol layer, from known peers or not
+    dropped: u64,
+}
+
+impl SoeServer {
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        protocol: &str,
+        udp_length: u32,
+        crc_length: u8,
+    ) -> Result<Self, TransportError> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(TICK))?;
+        Ok(Self {
+            socket,
+            manager: SessionManager::new(protocol, udp_length, crc_length),
+            clock: Instant::now(),
+            received: VecDeque::new(),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+            dropped: 0,
+        })
+    }
+
+    // sends the payload to the peer on reliable channel 0, fragmented if needed
+    pub fn send(&mut self, addr: SocketAddr, payload: &[u8]) -> Result<(), TransportError> {
+        let now = self.now();
+        let peer = self
+            .manager
+            .get_mut(&addr)
+            .ok_or(TransportError::UnknownPeer(addr))?;
+        for packet in send_reliable(peer, payload, now)? {
+            self.send_datagram(addr, &packet)?;
+        }
+        Ok(())
+    }
+
+    // waits up to timeout for the next application payload from any peer
+    pub fn receive(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<(SocketAddr, Vec<u8>)>, TransportError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(received) = self.received.pop_front() {
+                return Ok(Some(received));
+            }
+            self.update()?;
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            self.receive_datagram()?;
+        }
+    }
+
+    // sends the due resends and acks, drops the sessions that timed out
+    pub fn update(&mut self) -> Result<(), TransportError> {
+        let now = self.now();
+        let mut outgoing = self.manager.poll(now);
+        for (addr, peer) in self.manager.iter_mut() {
+            let mut packets = vec![];
+            flush_peer(peer, now, &mut packets);
+            outgoing.extend(packets.into_iter().map(|packet| (*addr, packet)));
+        }
+        for (addr, packet) in outgoing {
+            self.send_datagram(addr, &packet)?;
+        }
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, addr: SocketAddr) -> Result<(), TransportError> {
+        let peer = self
+            .manager
+            .get_mut(&addr)
+            .ok_or(TransportError::UnknownPeer(addr))?;
+        let packet = peer
+            .session
+            .disconnect(DisconnectReason::DisconnectReasonApplication)?;
+        self.manager.remove(&addr);
+        self.socket.send_to(&packet, addr)?;
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn get_manager(&self) -> &SessionManager {
+        &self.manager
+    }
+
+    pub fn get_dropped_count(&self) -> u64 {
+        self.dropped
+    }
+
+    // used to tweak the keepalive or encryption of the sessions to come
+    pub fn get_manager_mut(&mut self) -> &mut SessionManager {
+        &mut self.manager
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.elapsed().as_millis() as u64
+    }
+
+    fn send_datagram(&mut self, addr: SocketAddr, packet: &[u8]) -> Result<(), TransportError> {
+        self.socket.send_to(packet, addr)?;
+        if let Some(peer) = self.manager.get_mut(&addr) {
+            peer.session.record_sent();
+        }
+        Ok(())
+    }
+
+    // handles at most one datagram, returns once the socket read times out
+    fn receive_datagram(&mut self) -> Result<(), TransportError> {
+        let (length, addr) = match self.socket.recv_from(&mut self.buffer) {
+            Ok(received) => received,
+            Err(error) if is_timeout(&error) => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        let now = self.now();
+        let mut outgoing = vec![];
+        let mut payloads = vec![];
+        // a bad datagram is dropped, what it delivered before failing is kept
+        if self
+            .handle_datagram(addr, length, now, &mut outgoing, &mut payloads)
+            .is_err()
+        {
+            self.dropped += 1;
+        }
+        self.received
+            .extend(payloads.into_iter().map(|payload| (addr, payload)));
+        for packet in outgoing {
+            self.send_datagram(addr, &packet)?;
+        }
+        Ok(())
+    }
+
+    fn handle_datagram(
+        &mut self,
+        addr: SocketAddr,
+        length: usize,
+        now: u64,
+        outgoing: &mut Vec<Vec<u8>>,
+        payloads: &mut Vec<Vec<u8>>,
+    ) -> Result<(), ProtocolError> {
+        let incoming = self
+            .manager
+            .handle_datagram(addr, &self.buffer[..length], now)?;
+        outgoing.extend(incoming.reply);
+        // gone when the datagram closed the session
+        if let Some(peer) = self.manager.get_mut(&addr) {
+            if let SoePacket::NetStatusRequest(request) = &incoming.packet {
+                outgoing.push(peer.session.handle_net_status_request(request, now)?);
+            }
+            handle_reliable(peer, incoming.packet, now, outgoing, payloads)?;
+            flush_peer(peer, now, outgoing);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn bind_server() -> SoeServer {
+        SoeServer::bind("127.0.0.1:0", "LoginUdp_9", 512, 2).unwrap()
+    }
+
+    #[test]
+    fn transport_echo_test() {
+        let mut server = bind_server();
+        let server_addr = server.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let mut echoed = 0;
+            while echoed < 2 {
+                if let Some((addr, payload)) = server.receive(TIMEOUT).unwrap() {
+                    server.send(addr, &payload).unwrap();
+                    echoed += 1;
+                }
+            }
+            // let the last acks go through
+            server.receive(Duration::from_millis(100)).unwrap();
+        });
+        let mut client = SoeClient::connect(server_addr, "LoginUdp_9", 512, TIMEOUT).unwrap();
+        assert!(client.get_session().is_established());
+        assert_eq!(client.get_session().get_crc_length(), 2);
+        client.send(&[1, 2, 3]).unwrap();
+        assert_eq!(client.receive(TIMEOUT).unwrap(), Some(vec![1, 2, 3]));
+        // bigger than a datagram, goes through fragments
+        let payload: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        client.send(&payload).unwrap();
+        assert_eq!(client.receive(TIMEOUT).unwrap(), Some(payload));
+        echo.join().unwrap();
+        client.receive(Duration::from_millis(50)).unwrap();
+        assert!(client
+            .get_channels()
+            .senders()
+            .all(|sender| sender.get_pending_count() == 0))
+    }
+
+    #[test]
+    fn transport_connect_timeout_test() {
+        // bound but never answering
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = silent.local_addr().unwrap();
+        assert!(matches!(
+            SoeClient::connect(server_addr, "LoginUdp_9", 512, Duration::from_millis(50)),
+            Err(TransportError::Timeout)
+        ))
+    }
+
+    #[test]
+    fn transport_bad_datagram_test() {
+        let mut server = bind_server();
+        let server_addr = server.local_addr().unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // garbage, then a ping from a peer without a session
+        stranger.send_to(&[0xFF], server_addr).unwrap();
+        stranger.send_to(&[0, 6], server_addr).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        while server.get_dropped_count() < 2 && Instant::now() < deadline {
+            assert_eq!(server.receive(TICK).unwrap(), None);
+        }
+        assert_eq!(server.get_dropped_count(), 2);
+        assert!(server.get_manager().is_empty());
+        let client = thread::spawn(move || {
+            let mut client = SoeClient::connect(server_addr, "LoginUdp_9", 512, TIMEOUT).unwrap();
+            client.send(&[1]).unwrap();
+            client.receive(Duration::from_millis(100)).unwrap();
+        });
+        let (_, payload) = server.receive(TIMEOUT).unwrap().unwrap();
+        assert_eq!(payload, vec![1]);
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn transport_disconnect_test() {
+        let mut server = bind_server();
+        let server_addr = server.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = SoeClient::connect(server_addr, "LoginUdp_9", 512, TIMEOUT).unwrap();
+            loop {
+                match client.receive(TIMEOUT) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => return false,
+                    Err(error) => return matches!(error, TransportError::Closed),
+                }
+            }
+        });
+        let client_addr = loop {
+            server.receive(TICK).unwrap();
+            if let Some((addr, _)) = server.get_manager().iter().next() {
+                break *addr;
+            }
+        };
+        server.disconnect(client_addr).unwrap();
+        assert!(server.get_manager().is_empty());
+        assert!(client.join().unwrap());
+        assert!(matches!(
+            server.send(client_addr, &[1]),
+            Err(TransportError::UnknownPeer(_))
+        ))
+    }
+}